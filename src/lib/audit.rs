@@ -0,0 +1,125 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::time::{Duration, Instant};
+use log::info;
+use crate::{array_of_u8_to_u16, Beacon, BeaconListener, DiscoveryError, Result, Unmatched};
+
+/// `AuditReport` characterizes all the discovery activity a `BeaconListener` saw on its port
+/// during `BeaconListener::audit()`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuditReport {
+    /// Each distinct service name seen, with what was seen of it
+    pub services: BTreeMap<Vec<u8>, ServiceAudit>,
+    /// The number of datagrams received that did not start with the beacon magic number
+    pub wrong_magic: u64,
+    /// The number of datagrams received that started with the magic number, but could not be
+    /// parsed as a beacon
+    pub malformed: u64,
+    /// The number of beacons advertising a service port outside the listener's allowed port
+    /// range, or port `0` if no range was set
+    pub out_of_range_ports: u64,
+}
+
+/// `ServiceAudit` is what an audit saw of one service name
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServiceAudit {
+    /// The number of beacons seen for the service
+    pub beacon_count: u64,
+    /// The service IPs of the beacons seen
+    pub sources: BTreeSet<String>,
+    /// The service ports advertised in the beacons seen
+    pub ports: BTreeSet<u16>,
+}
+
+impl BeaconListener {
+    /// Listen for `window` and report on everything received on the listener's port: each
+    /// distinct service (regardless of the listener's service name), where its beacons came from,
+    /// how many were seen and the ports advertised, along with any anomalies seen.
+    /// Datagrams received during the audit still go through the listener's checks, so they are
+    /// counted in its stats and beacons are recorded in its sink, but they are not returned by
+    /// later calls to `wait()`. The read timeout of the socket is left as it was
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::net::UdpSocket;
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let listener = BeaconListener::new("audited".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    ///
+    /// let beacon = BeaconSender::new(8080, "audited".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// beacon.send_one_beacon().expect("Could not send beacon");
+    /// beacon.send_one_beacon().expect("Could not send beacon");
+    /// let noise = UdpSocket::bind("0.0.0.0:0").expect("Could not bind socket");
+    /// noise.send_to(b"noise", format!("127.0.0.1:{broadcast_port}")).expect("Could not send noise");
+    ///
+    /// let report = listener.audit(Duration::from_millis(300)).expect("Audit failed");
+    /// let service = &report.services[&b"audited".to_vec()];
+    /// assert_eq!(service.beacon_count, 2);
+    /// assert!(service.ports.contains(&8080));
+    /// assert_eq!(report.wrong_magic, 1);
+    /// assert_eq!(listener.stats().received, 3);
+    /// assert_eq!(listener.stats().matched, 2);
+    /// assert_eq!(listener.socket().read_timeout().expect("Could not get read timeout"), None);
+    /// ```
+    pub fn audit(&self, window: Duration) -> Result<AuditReport> {
+        let previous_timeout = self.socket.read_timeout()?;
+        let report = self.audit_until(Instant::now() + window);
+        self.socket.set_read_timeout(previous_timeout)?;
+        report
+    }
+
+    /*
+        Audit the datagrams received until `deadline`, setting the read timeout before each
+     */
+    fn audit_until(&self, deadline: Instant) -> Result<AuditReport> {
+        let mut report = AuditReport::default();
+        let mut buffer = self.receive_buffer();
+
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            self.socket.set_read_timeout(Some(remaining))?;
+
+            let (number_of_bytes, source_address, received_on) = match self.receive_datagram(&mut buffer) {
+                Ok(received) => received,
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => break,
+                Err(e) => return Err(DiscoveryError::Receive(e)),
+            };
+
+            let payload = &buffer[..number_of_bytes];
+            if let Some(beacon) = self.accept(payload, source_address) {
+                self.filter(Beacon { received_on, ..beacon }, &mut Unmatched::default());
+            }
+
+            match Beacon::parse_with_magic_number(payload, source_address, self.magic_number) {
+                Some(beacon) => {
+                    let out_of_range = match &self.allowed_ports {
+                        Some(ports) => !ports.contains(&beacon.service_port),
+                        None => beacon.service_port == 0,
+                    };
+                    if out_of_range {
+                        report.out_of_range_ports += 1;
+                    }
+
+                    let service = report.services.entry(beacon.service_name).or_default();
+                    service.beacon_count += 1;
+                    service.sources.insert(beacon.service_ip);
+                    service.ports.insert(beacon.service_port);
+                }
+                None if payload.len() >= 2 && array_of_u8_to_u16(&payload[0..2]) == self.magic_number =>
+                    report.malformed += 1,
+                None => report.wrong_magic += 1,
+            }
+        }
+
+        info!("{}Audit saw {} services, {} wrong magic, {} malformed and {} out of range ports",
+            self.log_prefix(), report.services.len(), report.wrong_magic, report.malformed, report.out_of_range_ports);
+        Ok(report)
+    }
+}
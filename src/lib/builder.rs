@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use crate::{reusable_socket, BeaconSender, DiscoveryError, Result};
+
+/// `BeaconSenderBuilder` creates a `BeaconSender` from named options, as an alternative to the
+/// positional arguments of `BeaconSender::new()`. Options not set keep their defaults: beacons
+/// are sent to the `255.255.255.255` broadcast address with the OS default TTL and no metadata,
+/// from an ephemeral local port
+///
+/// ```
+/// use simpdiscoverylib::{BeaconSender, BeaconListener};
+/// use std::collections::HashMap;
+/// use std::net::Ipv4Addr;
+/// use std::time::Duration;
+/// use portpicker::pick_unused_port;
+///
+/// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+/// let mut metadata = HashMap::new();
+/// metadata.insert("version".to_string(), "1.2.3".to_string());
+/// let beacon = BeaconSender::builder()
+///     .service_port(8080)
+///     .service_name("built".as_bytes())
+///     .broadcast_port(broadcast_port)
+///     .broadcast_address(Ipv4Addr::BROADCAST)
+///     .ttl(2)
+///     .metadata(metadata)
+///     .build()
+///     .expect("Could not create sender");
+/// assert_eq!(beacon.config().expect("Could not get config").ttl, 2);
+/// std::thread::spawn(move || {
+///     beacon.send_loop(Duration::from_millis(100)).expect("Could not enter send_loop");
+/// });
+///
+/// let listener = BeaconListener::new("built".as_bytes(), broadcast_port)
+///     .expect("Could not create listener");
+/// let beacon = listener.wait(None).expect("Failed to receive beacon");
+/// assert_eq!(beacon.service_port, 8080);
+/// assert_eq!(beacon.metadata["version"], "1.2.3");
+/// ```
+#[derive(Clone, Debug)]
+pub struct BeaconSenderBuilder {
+    service_port: u16,
+    service_name: Vec<u8>,
+    broadcast_port: u16,
+    broadcast_address: Ipv4Addr,
+    ttl: Option<u32>,
+    metadata: HashMap<String, String>,
+    magic_number: Option<u16>,
+    bind_port: u16,
+}
+
+impl BeaconSenderBuilder {
+    /// Create a new `BeaconSenderBuilder` with the default options. The service and broadcast
+    /// ports must be set before building, as services can't be contacted on port `0` and beacons
+    /// can't be sent to it
+    pub fn new() -> Self {
+        Self {
+            service_port: 0,
+            service_name: vec![],
+            broadcast_port: 0,
+            broadcast_address: Ipv4Addr::BROADCAST,
+            ttl: None,
+            metadata: HashMap::new(),
+            magic_number: None,
+            bind_port: 0,
+        }
+    }
+
+    /// The port the service advertised in the beacons should be contacted on
+    pub fn service_port(mut self, service_port: u16) -> Self {
+        self.service_port = service_port;
+        self
+    }
+
+    /// The name of the service advertised in the beacons, at most `MAX_SERVICE_NAME_LENGTH` bytes
+    pub fn service_name(mut self, service_name: &[u8]) -> Self {
+        self.service_name = service_name.to_vec();
+        self
+    }
+
+    /// The port listeners receive the beacons on
+    pub fn broadcast_port(mut self, broadcast_port: u16) -> Self {
+        self.broadcast_port = broadcast_port;
+        self
+    }
+
+    /// The address to send the beacons to, e.g. the broadcast address of a single network such
+    /// as `192.168.1.255` rather than `255.255.255.255`, or a multicast group
+    pub fn broadcast_address(mut self, broadcast_address: Ipv4Addr) -> Self {
+        self.broadcast_address = broadcast_address;
+        self
+    }
+
+    /// The IP time-to-live of the beacons sent, as `BeaconSender::set_ttl()`, or the multicast
+    /// time-to-live as `BeaconSender::set_multicast_ttl()` if the broadcast address is a multicast group
+    pub fn ttl(mut self, ttl: u32) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// The key/value metadata to attach to each beacon, as `BeaconSender::with_metadata()`
+    pub fn metadata(mut self, metadata: HashMap<String, String>) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// The magic number to start the beacons with, as `BeaconSender::with_magic_number()`
+    pub fn magic_number(mut self, magic_number: u16) -> Self {
+        self.magic_number = Some(magic_number);
+        self
+    }
+
+    /// The local port to send the beacons from, e.g. one a firewall allows, bound with address
+    /// reuse as `BeaconSender::new_with_bind_port()`. Port `0` (the default) lets the OS choose
+    /// an ephemeral port
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let bind_port = pick_unused_port().expect("Could not get a free port to bind to");
+    /// let listener = BeaconListener::new("bound".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// BeaconSender::builder()
+    ///     .service_port(8080)
+    ///     .service_name("bound".as_bytes())
+    ///     .broadcast_port(broadcast_port)
+    ///     .bind_port(bind_port)
+    ///     .build()
+    ///     .expect("Could not create sender")
+    ///     .send_one_beacon().expect("Could not send beacon");
+    ///
+    /// let beacon = listener.wait(Some(Duration::from_secs(5))).expect("Failed to receive beacon");
+    /// assert_eq!(beacon.source_addr.port(), bind_port);
+    /// ```
+    pub fn bind_port(mut self, bind_port: u16) -> Self {
+        self.bind_port = bind_port;
+        self
+    }
+
+    /// Create the `BeaconSender`. Fails with `DiscoveryError::ZeroServicePort` if the service port
+    /// wasn't set, `DiscoveryError::InvalidAddress` if the broadcast port wasn't set, or
+    /// `DiscoveryError::PayloadTooLarge` if the beacon (including its metadata) is too large
+    /// for listeners to receive
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, DiscoveryError};
+    /// use std::net::Ipv4Addr;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let unset_port = BeaconSender::builder().service_port(8080).service_name("built".as_bytes()).build();
+    /// assert!(matches!(unset_port, Err(DiscoveryError::InvalidAddress(_))));
+    ///
+    /// let port = pick_unused_port().expect("Could not get a free port");
+    /// BeaconSender::builder()
+    ///     .service_port(8080)
+    ///     .service_name("built".as_bytes())
+    ///     .broadcast_port(port)
+    ///     .broadcast_address(Ipv4Addr::new(239, 255, 42, 99))
+    ///     .ttl(4)
+    ///     .build()
+    ///     .expect("Could not create multicast sender");
+    /// ```
+    pub fn build(self) -> Result<BeaconSender> {
+        if self.broadcast_port == 0 {
+            return Err(DiscoveryError::InvalidAddress("beacons can't be sent to broadcast port 0".into()));
+        }
+
+        // Without a bind port the sender binds to an ephemeral port chosen by the OS, as it doesn't
+        // expect responses. A fixed port is bound with address reuse, as binding it fails with
+        // `AddrInUse` if any other socket on the host is already bound to it (e.g. a
+        // `BeaconListener` on the broadcast port, or another sender)
+        let bind_address = SocketAddr::from((Ipv4Addr::UNSPECIFIED, self.bind_port));
+        let socket = reusable_socket(bind_address)
+            .map_err(|source| DiscoveryError::Bind { address: bind_address.to_string(), source })?;
+        let broadcast_address = SocketAddr::from((self.broadcast_address, self.broadcast_port));
+        let multicast = self.broadcast_address.is_multicast();
+        let mut sender = BeaconSender::from_socket(socket, self.service_port, &self.service_name,
+                                                   broadcast_address, !multicast)?
+            .with_metadata(self.metadata)?;
+        if let Some(magic_number) = self.magic_number {
+            sender = sender.with_magic_number(magic_number);
+        }
+
+        match self.ttl {
+            Some(ttl) if multicast => sender.set_multicast_ttl(ttl)?,
+            Some(ttl) => sender.set_ttl(ttl)?,
+            None => {}
+        }
+
+        Ok(sender)
+    }
+}
+
+impl Default for BeaconSenderBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
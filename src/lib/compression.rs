@@ -0,0 +1,108 @@
+//! Compressed beacons, for senders with large metadata. The fields of a compressed beacon (after
+//! its service name) are deflated into a single field, when that makes the beacon smaller, and
+//! listeners with the `compression` feature inflate them again transparently.
+//!
+//! ```
+//! use simpdiscoverylib::{BeaconSender, BeaconListener, DiscoveryError};
+//! use std::collections::HashMap;
+//! use std::time::Duration;
+//! use portpicker::pick_unused_port;
+//!
+//! let mut metadata = HashMap::new();
+//! for n in 0..20 {
+//!     metadata.insert(format!("endpoint-{n}"), format!("https://service.example.com/api/v1/{n}"));
+//! }
+//!
+//! let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+//! let listener = BeaconListener::new("compressed".as_bytes(), broadcast_port)
+//!     .expect("Could not create listener");
+//! // The metadata is too large to send uncompressed, but fits once compressed
+//! let plain = BeaconSender::new(8080, "compressed".as_bytes(), broadcast_port)
+//!     .expect("Could not create sender")
+//!     .with_metadata(metadata.clone());
+//! assert!(matches!(plain, Err(DiscoveryError::PayloadTooLarge { .. })));
+//! let compressed = BeaconSender::new(8080, "compressed".as_bytes(), broadcast_port)
+//!     .expect("Could not create sender")
+//!     .with_compression()
+//!     .with_metadata(metadata.clone())
+//!     .expect("Compressed metadata is too large");
+//!
+//! compressed.send_one_beacon().expect("Could not send beacon");
+//! let beacon = listener.wait(Some(Duration::from_secs(5))).expect("Failed to receive beacon");
+//! assert_eq!(beacon.metadata, metadata);
+//! ```
+
+use std::io::{Read, Write};
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use log::trace;
+use crate::{append_field, BeaconSender, COMPRESSED_FIELD, FIELD_HEADER_SIZE};
+
+/*
+    The most a compressed field may inflate to, as its fields could not be sent uncompressed
+    in a larger datagram, so that a malicious beacon can't exhaust the listener's memory
+ */
+const MAX_INFLATED_SIZE: u64 = u16::MAX as u64;
+
+impl BeaconSender {
+    /// Compress the beacons sent, whenever that makes them smaller, e.g. for senders with large
+    /// metadata beaconing frequently. Listeners need the `compression` feature to read the
+    /// fields of compressed beacons, or they receive them without their optional fields
+    pub fn with_compression(mut self) -> Self {
+        self.compressed = true;
+        self
+    }
+}
+
+/*
+    Replace the fields of the beacon in `payload` from `fields_start` with a compressed field,
+    if that is smaller
+ */
+pub(crate) fn compress_fields(payload: &mut Vec<u8>, fields_start: usize) {
+    let fields = &payload[fields_start..];
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+    let compressed = match encoder.write_all(fields).and_then(|_| encoder.finish()) {
+        Ok(compressed) => compressed,
+        Err(_) => return,
+    };
+
+    if FIELD_HEADER_SIZE + compressed.len() < fields.len() && compressed.len() <= u16::MAX as usize {
+        payload.truncate(fields_start);
+        append_field(payload, COMPRESSED_FIELD, &compressed);
+    }
+}
+
+/*
+    The fields compressed in the value of a compressed field, or `None` if it doesn't inflate
+ */
+pub(crate) fn inflate(value: &[u8]) -> Option<Vec<u8>> {
+    let mut fields = vec![];
+    match DeflateDecoder::new(value).take(MAX_INFLATED_SIZE + 1).read_to_end(&mut fields) {
+        Ok(size) if size as u64 <= MAX_INFLATED_SIZE => Some(fields),
+        _ => {
+            trace!("Skipping beacon with a compressed field that does not inflate");
+            None
+        }
+    }
+}
+
+/*
+    Gzip a registry snapshot, see `ServiceRegistry::with_compressed_snapshots()`
+ */
+#[cfg(feature = "serde")]
+pub(crate) fn gzip(snapshot: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(snapshot)?;
+    encoder.finish()
+}
+
+/*
+    Gunzip a registry snapshot saved compressed
+ */
+#[cfg(feature = "serde")]
+pub(crate) fn gunzip(snapshot: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut inflated = vec![];
+    flate2::read::GzDecoder::new(snapshot).read_to_end(&mut inflated)?;
+    Ok(inflated)
+}
@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use log::{trace, warn};
+use crate::{Beacon, ServiceEvent, ServiceRegistry};
+
+/*
+    The longest the background thread blocks waiting for events before checking if the
+    manager has been dropped
+ */
+const POLL_PERIOD: Duration = Duration::from_millis(250);
+
+/// `ConnectionManager` keeps a connection open to each instance of a service tracked by a
+/// `ServiceRegistry`, on a background thread driven by the registry's events (see
+/// `ServiceRegistry::events()`). A connection is opened with the `connect` closure when an
+/// instance is added, and dropped (hence closed) when the instance expires or says goodbye.
+/// If `connect` fails it is logged and not retried until the instance is removed and added
+/// again. The background thread is stopped when the manager or the registry is dropped
+///
+/// ```
+/// use simpdiscoverylib::{BeaconSender, BeaconListener, ConnectionManager, ServiceRegistry};
+/// use std::net::{TcpListener, TcpStream};
+/// use std::time::Duration;
+/// use portpicker::pick_unused_port;
+///
+/// let server = TcpListener::bind("127.0.0.1:0").expect("Could not bind server");
+/// let service_port = server.local_addr().expect("Could not get server address").port();
+///
+/// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+/// let listener = BeaconListener::new("connected".as_bytes(), broadcast_port)
+///     .expect("Could not create listener");
+/// let registry = ServiceRegistry::new(listener, Duration::from_secs(60));
+/// let manager = ConnectionManager::new(&registry, TcpStream::connect);
+///
+/// let sender = BeaconSender::new(service_port, "connected".as_bytes(), broadcast_port)
+///     .expect("Could not create sender")
+///     .with_advertised_ip("127.0.0.1".parse().expect("Invalid IP"));
+/// sender.send_one_beacon().expect("Could not send beacon");
+/// server.accept().expect("No connection was opened");
+/// std::thread::sleep(Duration::from_millis(300));
+/// assert_eq!(manager.connections().len(), 1);
+///
+/// sender.send_goodbye().expect("Could not send goodbye");
+/// std::thread::sleep(Duration::from_millis(500));
+/// assert!(manager.connections().is_empty());
+/// ```
+pub struct ConnectionManager<C> {
+    connections: Arc<Mutex<HashMap<SocketAddr, C>>>,
+    stop: Arc<AtomicBool>,
+    manager: Option<JoinHandle<()>>,
+}
+
+impl<C: Send + 'static> ConnectionManager<C> {
+    /// Create a new `ConnectionManager` connecting to the instances of services tracked by
+    /// `registry`, both those already tracked and those added later, with `connect`
+    pub fn new<F>(registry: &ServiceRegistry, connect: F) -> Self
+        where F: Fn(SocketAddr) -> io::Result<C> + Send + 'static {
+        let connections = Arc::new(Mutex::new(HashMap::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+        // Subscribe before reading the current services, so none are missed in between
+        let events = registry.events();
+        let current = registry.current_services();
+
+        let manager = {
+            let connections = connections.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                for beacon in current {
+                    open(&connections, &connect, &beacon);
+                }
+                while !stop.load(Ordering::Relaxed) {
+                    match events.recv_timeout(POLL_PERIOD) {
+                        Ok(ServiceEvent::Added(beacon)) => open(&connections, &connect, &beacon),
+                        Ok(ServiceEvent::Removed(beacon)) => {
+                            if let Some(address) = service_address(&beacon) {
+                                if lock(&connections).remove(&address).is_some() {
+                                    trace!("Closed connection to '{address}'");
+                                }
+                            }
+                        }
+                        Err(RecvTimeoutError::Timeout) => {}
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+            })
+        };
+
+        Self {
+            connections,
+            stop,
+            manager: Some(manager),
+        }
+    }
+
+    /// The connections open to the instances currently tracked, by the address of each instance.
+    /// Connections aren't opened or closed while the guard returned is held
+    pub fn connections(&self) -> MutexGuard<'_, HashMap<SocketAddr, C>> {
+        lock(&self.connections)
+    }
+}
+
+impl<C> Drop for ConnectionManager<C> {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(manager) = self.manager.take() {
+            let _ = manager.join();
+        }
+    }
+}
+
+fn lock<C>(connections: &Mutex<HashMap<SocketAddr, C>>) -> MutexGuard<'_, HashMap<SocketAddr, C>> {
+    connections.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/*
+    The address of the service described by `beacon`, if its service IP is valid
+ */
+fn service_address(beacon: &Beacon) -> Option<SocketAddr> {
+    beacon.service_ip.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, beacon.service_port))
+}
+
+/*
+    Open a connection to the service described by `beacon`, unless one is already open
+ */
+fn open<C, F: Fn(SocketAddr) -> io::Result<C>>(connections: &Mutex<HashMap<SocketAddr, C>>, connect: &F,
+                                               beacon: &Beacon) {
+    let Some(address) = service_address(beacon) else {
+        warn!("Not connecting to service with invalid IP '{}'", beacon.service_ip);
+        return;
+    };
+    if lock(connections).contains_key(&address) {
+        return;
+    }
+
+    match connect(address) {
+        Ok(connection) => {
+            trace!("Opened connection to '{address}'");
+            lock(connections).insert(address, connection);
+        }
+        Err(e) => warn!("Could not connect to service at '{address}' ({e})"),
+    }
+}
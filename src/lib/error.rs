@@ -0,0 +1,112 @@
+use std::fmt;
+use std::io;
+use crate::NoMatchReport;
+
+/// `DiscoveryError` is the error returned by all the fallible operations of the crate, so
+/// callers can tell the different causes of failure apart without matching on messages
+///
+/// ```
+/// use simpdiscoverylib::{BeaconSender, DiscoveryError};
+/// use std::net::SocketAddr;
+///
+/// let local: SocketAddr = "[::1]:0".parse().expect("Invalid address");
+/// match BeaconSender::new_bound(local, 8080, "failing".as_bytes(), 9002) {
+///     Err(DiscoveryError::InvalidAddress(_)) => {},
+///     _ => panic!("Expected an InvalidAddress error"),
+/// }
+/// ```
+#[derive(Debug)]
+pub enum DiscoveryError {
+    /// A socket could not be bound to `address`, e.g. because it is already in use
+    Bind {
+        /// The address the socket was being bound to
+        address: String,
+        /// The error from the OS
+        source: io::Error,
+    },
+    /// A beacon (or query) could not be sent
+    Send(io::Error),
+    /// Receiving beacons (or responses to a query) failed
+    Receive(io::Error),
+    /// No matching beacon arrived before the timeout expired, with a description of the
+    /// non-matching beacons that were seen instead
+    Timeout(NoMatchReport),
+    /// An address that can't be used for what was asked of it, e.g. broadcasting from an IPv6
+    /// address or joining a multicast group with an address that is not multicast
+    InvalidAddress(String),
+    /// A beacon that is too large for listeners to receive
+    PayloadTooLarge {
+        /// The size of the beacon in bytes
+        size: usize,
+        /// The largest beacon listeners can receive, in bytes
+        max: usize,
+    },
+    /// The OS refused to enable broadcasting (`SO_BROADCAST`) on a socket, e.g. on a host
+    /// whose security policy denies it
+    Broadcast(io::Error),
+    /// A `BeaconSender` was created with, or updated to, a service port of `0`, which services
+    /// can't be contacted on, e.g. because the port was never set
+    ZeroServicePort,
+    /// Data that should have been a beacon could not be parsed as one
+    MalformedBeacon(String),
+    /// A `DiscoveryRelay` received a signed beacon but its `BeaconSender` has no signing key, so
+    /// it can't sign the beacon again after adding the relayed fields, which break the signature
+    SigningKeyRequired,
+    /// Any other I/O error, such as failing to set a socket option
+    Io(io::Error),
+}
+
+/// The `Result` type returned by the fallible operations of the crate
+pub type Result<T> = std::result::Result<T, DiscoveryError>;
+
+impl DiscoveryError {
+    /// Whether this error is because a timeout expired
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            DiscoveryError::Timeout(_) => true,
+            DiscoveryError::Receive(e) | DiscoveryError::Io(e) =>
+                matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for DiscoveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiscoveryError::Bind { address, source } =>
+                write!(f, "SimpDiscover could not bind to UdpSocket {address} ({source})"),
+            DiscoveryError::Send(e) => write!(f, "SimpDiscover could not send ({e})"),
+            DiscoveryError::Receive(e) => write!(f, "SimpDiscover could not receive ({e})"),
+            DiscoveryError::Timeout(report) => write!(f, "{report}"),
+            DiscoveryError::InvalidAddress(reason) => write!(f, "SimpDiscover invalid address: {reason}"),
+            DiscoveryError::PayloadTooLarge { size, max } =>
+                write!(f, "SimpDiscover beacon of {size} bytes is larger than the maximum of {max} bytes"),
+            DiscoveryError::Broadcast(e) => write!(f, "SimpDiscover could not enable broadcast ({e})"),
+            DiscoveryError::ZeroServicePort =>
+                write!(f, "SimpDiscover service port 0 can't be advertised, as services can't be contacted on it"),
+            DiscoveryError::MalformedBeacon(reason) => write!(f, "SimpDiscover malformed beacon: {reason}"),
+            DiscoveryError::SigningKeyRequired =>
+                write!(f, "SimpDiscover can't relay a signed beacon without a signing key to sign it again"),
+            DiscoveryError::Io(e) => write!(f, "SimpDiscover I/O error ({e})"),
+        }
+    }
+}
+
+impl std::error::Error for DiscoveryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DiscoveryError::Bind { source, .. } => Some(source),
+            DiscoveryError::Send(e) | DiscoveryError::Receive(e) | DiscoveryError::Broadcast(e) |
+            DiscoveryError::Io(e) => Some(e),
+            DiscoveryError::Timeout(report) => Some(report),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DiscoveryError {
+    fn from(error: io::Error) -> Self {
+        DiscoveryError::Io(error)
+    }
+}
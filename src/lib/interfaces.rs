@@ -0,0 +1,269 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::Duration;
+use if_addrs::IfAddr;
+use log::{info, trace, warn};
+use crate::{check_payload_size, BeaconSender, DiscoveryError, Result};
+
+/*
+    A routable address used to find the local address of the interface with the default route.
+    Connecting a UDP socket sends nothing, so it doesn't need to be reachable
+ */
+const ROUTE_PROBE_ADDRESS: (Ipv4Addr, u16) = (Ipv4Addr::new(192, 0, 2, 1), 9);
+
+/// `BroadcastCandidate` is a local IPv4 interface that beacons could be broadcast on, with
+/// the subnet-directed broadcast address for the network it is on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BroadcastCandidate {
+    /// The name of the interface, e.g. "eth0"
+    pub interface_name: String,
+    /// The local IP address of this host on the interface
+    pub local_ip: Ipv4Addr,
+    /// The broadcast address of the network the interface is on
+    pub broadcast_address: Ipv4Addr,
+}
+
+/// `NetworkChange` is a change to the interfaces `BeaconSender::send_loop_on_interfaces()` is
+/// broadcasting on, reported to the callback set with `BeaconSender::on_network_change()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetworkChange {
+    /// Beacons are being broadcast on an interface, that has just appeared or was there when
+    /// the loop started
+    InterfaceAdded(BroadcastCandidate),
+    /// Beacons are no longer broadcast on an interface, as it has gone or its address changed
+    InterfaceRemoved(BroadcastCandidate),
+    /// Sending on an interface failed, so its socket was bound again before resending
+    SocketRebound(BroadcastCandidate),
+}
+
+// The callback set with `BeaconSender::on_network_change()`
+pub(crate) type NetworkChangeCallback = Arc<dyn Fn(&NetworkChange) + Send + Sync>;
+
+/// Get all the local IPv4 interfaces that beacons could be broadcast on, with the broadcast
+/// address to use for each one, e.g. to let a user pick which network to announce on instead
+/// of guessing the broadcast address. Loopback and point-to-point interfaces are not included.
+///
+/// ```
+/// use simpdiscoverylib::broadcast_candidates;
+///
+/// for candidate in broadcast_candidates().expect("Could not get interfaces") {
+///     println!("{}: {} (broadcast {})", candidate.interface_name, candidate.local_ip,
+///              candidate.broadcast_address);
+/// }
+/// ```
+pub fn broadcast_candidates() -> Result<Vec<BroadcastCandidate>> {
+    let mut candidates = vec![];
+
+    for interface in if_addrs::get_if_addrs()? {
+        if let IfAddr::V4(address) = &interface.addr {
+            if address.ip.is_loopback() || interface.is_p2p() {
+                continue;
+            }
+
+            // Replace all the host bits with 1s if the OS didn't tell us the broadcast address
+            let broadcast_address = address.broadcast.unwrap_or_else(||
+                Ipv4Addr::from(u32::from(address.ip) | !u32::from(address.netmask)));
+            if broadcast_address == address.ip {
+                continue;
+            }
+
+            candidates.push(BroadcastCandidate {
+                interface_name: interface.name.clone(),
+                local_ip: address.ip,
+                broadcast_address,
+            });
+        }
+    }
+
+    Ok(candidates)
+}
+
+/// Get the subnet-directed broadcast address of this host's primary network: the one the
+/// interface with the default route is on, or the first of `broadcast_candidates()` if there is
+/// no default route. Use it with `BeaconSenderBuilder::broadcast_address()` to broadcast beacons
+/// to just that network, rather than to `255.255.255.255`
+///
+/// ```
+/// use simpdiscoverylib::{default_broadcast_address, BeaconSender};
+/// use portpicker::pick_unused_port;
+///
+/// if let Ok(broadcast_address) = default_broadcast_address() {
+///     let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+///     let beacon = BeaconSender::builder()
+///         .service_port(8080)
+///         .service_name("subnet".as_bytes())
+///         .broadcast_port(broadcast_port)
+///         .broadcast_address(broadcast_address)
+///         .build()
+///         .expect("Could not create sender");
+///     assert_eq!(beacon.config().expect("Could not get config").broadcast_address,
+///                format!("{broadcast_address}:{broadcast_port}"));
+/// }
+/// ```
+pub fn default_broadcast_address() -> Result<Ipv4Addr> {
+    let candidates = broadcast_candidates()?;
+    let primary_ip = primary_ip();
+    candidates.iter()
+        .find(|candidate| Some(candidate.local_ip) == primary_ip)
+        .or_else(|| candidates.first())
+        .map(|candidate| candidate.broadcast_address)
+        .ok_or_else(|| DiscoveryError::Io(io::Error::new(io::ErrorKind::NotFound,
+                                                        "no interface to broadcast on")))
+}
+
+/*
+    The local IP of the interface the OS would route to other networks through, if any
+ */
+fn primary_ip() -> Option<Ipv4Addr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect(ROUTE_PROBE_ADDRESS).ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V4(ip) => Some(ip),
+        IpAddr::V6(_) => None,
+    }
+}
+
+impl BeaconSender {
+    /// Call `callback` with each `NetworkChange` as `send_loop_on_interfaces()` starts or stops
+    /// broadcasting on an interface, or binds the socket for one again, e.g. to log network
+    /// transitions. It is called on the thread sending the beacons, so should return quickly
+    pub fn on_network_change<F>(mut self, callback: F) -> Self
+        where F: Fn(&NetworkChange) + Send + Sync + 'static {
+        self.on_network_change = Some(Arc::new(callback));
+        self
+    }
+
+    /// Enter an infinite loop sending `Beacon`s periodically to the broadcast address of each of
+    /// `broadcast_candidates()`, on the sender's broadcast port, from a socket bound to each
+    /// interface. The interfaces are listed again before every beacon, so beacons follow
+    /// interfaces that come and go (e.g. Wi-Fi or a VPN), and if sending on one fails its socket
+    /// is bound again and the beacon resent. Like `send_loop_resilient()` it never returns:
+    /// failures are logged and counted in `stats()`, and changes reported to `on_network_change()`
+    ///
+    /// ```
+    /// use simpdiscoverylib::{broadcast_candidates, BeaconSender, BeaconListener, NetworkChange};
+    /// use std::sync::mpsc;
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let interfaces = broadcast_candidates().expect("Could not get interfaces");
+    /// if !interfaces.is_empty() {
+    ///     let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    ///     let listener = BeaconListener::new("everywhere".as_bytes(), broadcast_port)
+    ///         .expect("Could not create listener");
+    ///
+    ///     let (changes, changed) = mpsc::channel();
+    ///     let beacon = BeaconSender::new(8080, "everywhere".as_bytes(), broadcast_port)
+    ///         .expect("Could not create sender")
+    ///         .on_network_change(move |change| changes.send(change.clone()).expect("Could not report change"));
+    ///     std::thread::spawn(move || beacon.send_loop_on_interfaces(Duration::from_millis(100)));
+    ///
+    ///     let change = changed.recv_timeout(Duration::from_secs(5)).expect("No network change");
+    ///     assert_eq!(change, NetworkChange::InterfaceAdded(interfaces[0].clone()));
+    ///     let beacon = listener.wait(Some(Duration::from_secs(5))).expect("Failed to receive beacon");
+    ///     assert_eq!(beacon.service_port, 8080);
+    /// }
+    /// ```
+    pub fn send_loop_on_interfaces(&self, period: Duration) -> ! {
+        let mut sockets = vec![];
+        loop {
+            self.refresh_interfaces(&mut sockets);
+            if !self.ready() {
+                trace!("{}Service '{}' is not ready: not sending beacon", self.log_prefix(),
+                    String::from_utf8_lossy(&self.service_name));
+            } else {
+                let payload = self.next_beacon_payload();
+                match check_payload_size(&payload) {
+                    Ok(()) => for (candidate, socket) in &mut sockets {
+                        self.send_on_interface(candidate, socket, &payload);
+                    },
+                    Err(e) => warn!("{}Could not send beacon ({e})", self.log_prefix()),
+                }
+            }
+            std::thread::sleep(period);
+        }
+    }
+
+    /*
+        Bind a socket for each interface that has appeared since the last call, and drop those
+        of interfaces that have gone. If the interfaces can't be listed the current ones are kept
+     */
+    fn refresh_interfaces(&self, sockets: &mut Vec<(BroadcastCandidate, UdpSocket)>) {
+        let candidates = match broadcast_candidates() {
+            Ok(candidates) => candidates,
+            Err(e) => {
+                warn!("{}Could not list the interfaces ({e}): broadcasting on the same ones", self.log_prefix());
+                return;
+            }
+        };
+
+        sockets.retain(|(current, _)| {
+            let present = candidates.contains(current);
+            if !present {
+                info!("{}Interface '{}' ({}) has gone: no longer broadcasting on it", self.log_prefix(),
+                    current.interface_name, current.local_ip);
+                self.network_changed(NetworkChange::InterfaceRemoved(current.clone()));
+            }
+            present
+        });
+
+        for candidate in candidates {
+            if sockets.iter().any(|(current, _)| *current == candidate) {
+                continue;
+            }
+            match bind_on(&candidate) {
+                Ok(socket) => {
+                    info!("{}Broadcasting on interface '{}' ({})", self.log_prefix(), candidate.interface_name,
+                        candidate.local_ip);
+                    self.network_changed(NetworkChange::InterfaceAdded(candidate.clone()));
+                    sockets.push((candidate, socket));
+                }
+                Err(e) => warn!("{}Could not bind to interface '{}' ({e}): retrying before the next beacon",
+                    self.log_prefix(), candidate.interface_name),
+            }
+        }
+    }
+
+    /*
+        Send `payload` to the broadcast address of `candidate`, binding its socket again and
+        resending once if that fails
+     */
+    fn send_on_interface(&self, candidate: &BroadcastCandidate, socket: &mut UdpSocket, payload: &[u8]) {
+        let destination = SocketAddr::from((candidate.broadcast_address, self.broadcast_address.port()));
+        trace!("{}Sending Beacon '{}' to: '{}'", self.log_prefix(), String::from_utf8_lossy(&self.service_name),
+            destination);
+        let Err(e) = self.record_send(socket.send_to(payload, destination)) else {
+            return;
+        };
+
+        warn!("{}Could not send beacon on interface '{}' ({e}): binding its socket again", self.log_prefix(),
+            candidate.interface_name);
+        match bind_on(candidate) {
+            Ok(rebound) => {
+                *socket = rebound;
+                self.network_changed(NetworkChange::SocketRebound(candidate.clone()));
+                if let Err(e) = self.record_send(socket.send_to(payload, destination)) {
+                    warn!("{}Could not send beacon on interface '{}' ({e})", self.log_prefix(), candidate.interface_name);
+                }
+            }
+            Err(e) => warn!("{}Could not bind to interface '{}' again ({e})", self.log_prefix(),
+                candidate.interface_name),
+        }
+    }
+
+    fn network_changed(&self, change: NetworkChange) {
+        if let Some(callback) = &self.on_network_change {
+            callback(&change);
+        }
+    }
+}
+
+/*
+    A socket to broadcast beacons on the interface of `candidate` from
+ */
+fn bind_on(candidate: &BroadcastCandidate) -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind((candidate.local_ip, 0))?;
+    socket.set_broadcast(true)?;
+    Ok(socket)
+}
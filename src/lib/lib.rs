@@ -25,11 +25,71 @@
 //! assert_eq!(beacon.service_port, service_port, "Received service port doesn't match");
 //! ```
 
-use std::net::UdpSocket;
-use std::time::Duration;
-use log::{info, trace};
+use std::borrow::Cow;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, UdpSocket};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::mpsc::{self, Receiver};
+use std::thread::JoinHandle;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU64, Ordering};
+use log::{info, trace, warn};
 use std::fmt::Formatter;
 use std::io;
+use std::convert::TryFrom;
+use std::ops::{ControlFlow, RangeInclusive};
+
+mod audit;
+
+mod builder;
+pub use builder::BeaconSenderBuilder;
+
+mod connections;
+pub use connections::ConnectionManager;
+
+mod error;
+pub use error::{DiscoveryError, Result};
+pub use audit::{AuditReport, ServiceAudit};
+
+mod interfaces;
+pub use interfaces::{broadcast_candidates, default_broadcast_address, BroadcastCandidate, NetworkChange};
+
+mod query;
+pub use query::{query_services, query_services_with_magic_number};
+
+mod registry;
+pub use registry::{ServiceEvent, ServiceRegistry};
+
+mod relay;
+pub use relay::DiscoveryRelay;
+
+mod schema;
+pub use schema::{MetadataSchema, ValueConstraint};
+
+mod sink;
+pub use sink::BeaconSink;
+
+#[cfg(feature = "tcp-rendezvous")]
+mod rendezvous;
+#[cfg(feature = "tcp-rendezvous")]
+pub use rendezvous::{TcpRendezvous, DEFAULT_MAX_REGISTRATIONS};
+
+#[cfg(feature = "serde")]
+mod service_name;
+
+#[cfg(feature = "signed-beacons")]
+mod signing;
+
+#[cfg(feature = "compression")]
+mod compression;
+
+#[cfg(all(feature = "packet-info", target_os = "linux"))]
+mod packet_info;
+
+#[cfg(feature = "tokio")]
+pub mod tokio;
 
 /// A broadcast address is always relative to a given network. When you have a network, you can
 /// compute its broadcast address by replacing all the host bits with 1s; simply put, the broadcast
@@ -55,7 +115,85 @@ const BROADCAST_ADDRESS : &str = "255.255.255.255";
 const LISTENING_ADDRESS : &str = "0.0.0.0";
 
 const MAX_INCOMING_BEACON_SIZE : usize = 1024;
+// One byte more than the largest beacon, so a datagram truncated to fit the buffer can be detected
+const RECEIVE_BUFFER_SIZE : usize = MAX_INCOMING_BEACON_SIZE + 1;
 const MAGIC_NUMBER: u16 = 0xbeef;
+const PROTOCOL_VERSION: u8 = 1;
+const MAX_RATE_LIMITED_SOURCES: usize = 1024;
+const MAX_DEBOUNCED_SENDERS: usize = 1024;
+
+/*
+    Beacon payload layout (all numbers big-endian):
+        magic number (2) | protocol version (1) | service port (2) | service name length (2) |
+        service name
+    followed by zero or more optional fields, each encoded as:
+        field tag (1) | value length (2) | value
+    and finally a Fletcher-16 checksum (2) of all the bytes before it.
+    Fields with a tag a listener doesn't know about are skipped, so new ones can be added.
+    Beacons with a protocol version a listener doesn't know are skipped entirely, so the
+    layout can be changed in ways that older listeners can't skip over.
+ */
+const HEADER_SIZE: usize = 7;
+const FIELD_HEADER_SIZE: usize = 3;
+const CHECKSUM_SIZE: usize = 2;
+const LEASE_FIELD: u8 = 1;
+const PRIORITY_FIELD: u8 = 2;
+const WEIGHT_FIELD: u8 = 3;
+const GENERATION_FIELD: u8 = 4;
+const ADVERTISED_IP_FIELD: u8 = 5;
+const RELAYED_FIELD: u8 = 6;
+/*
+    The value of the metadata field is zero or more entries, each encoded as:
+        key length (2) | key | value length (2) | value
+ */
+const METADATA_FIELD: u8 = 7;
+const SEQUENCE_FIELD: u8 = 8;
+const SENT_AT_FIELD: u8 = 9;
+/*
+    A datagram with the query field is a query from `query_services()` for the service named in
+    its header (with service port `0`) rather than a beacon
+ */
+const QUERY_FIELD: u8 = 10;
+/*
+    An HMAC-SHA256 tag authenticating the beacon, see the `signing` module
+ */
+const SIGNATURE_FIELD: u8 = 11;
+/*
+    A beacon with the goodbye field says the service is shutting down, see `BeaconSender::send_goodbye()`
+ */
+const GOODBYE_FIELD: u8 = 12;
+/*
+    The value of the compressed field is all the other fields of the beacon, deflated. See the
+    `compression` module
+ */
+#[cfg(feature = "compression")]
+const COMPRESSED_FIELD: u8 = 13;
+/*
+    The value of the hostname field is the sender's host name in UTF-8
+ */
+const HOSTNAME_FIELD: u8 = 14;
+/*
+    A datagram with the ack field acknowledges the beacon of the service named in its header,
+    see `BeaconListener::acknowledge()`, rather than being a beacon
+ */
+const ACK_FIELD: u8 = 15;
+
+/*
+    What a datagram carrying a beacon header is
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DatagramKind {
+    Beacon,
+    Query,
+    Ack,
+}
+
+/// The longest service name a `BeaconSender` can be created with, so that its beacons (with the
+/// sequence number every beacon carries) fit in a listener's receive buffer. Optional fields
+/// such as metadata reduce the space left for the name, and sending a beacon that doesn't fit
+/// fails with `DiscoveryError::PayloadTooLarge`
+pub const MAX_SERVICE_NAME_LENGTH: usize =
+    MAX_INCOMING_BEACON_SIZE - HEADER_SIZE - (FIELD_HEADER_SIZE + 4) - CHECKSUM_SIZE;
 
 /// `BeaconSender` is used to send UDP Datagram beacons to the Broadcast IP address on the LAN
 ///
@@ -65,7 +203,8 @@ const MAGIC_NUMBER: u16 = 0xbeef;
 /// In your own code, either:
 ///   * don't start a background thread and just loop forever sending beacons in main thread, or
 ///   * have some other way to keep the process (and hence the sending thread) alive so
-///     beacons are actually sent before process ends
+///     beacons are actually sent before process ends, and use `send_loop_until()` if the
+///     sending thread needs to be stopped before then
 ///
 /// ```
 /// use simpdiscoverylib::{BeaconSender, BeaconListener};
@@ -82,8 +221,61 @@ const MAGIC_NUMBER: u16 = 0xbeef;
 ///  });
 pub struct BeaconSender {
     socket: UdpSocket,
-    beacon_payload: Vec<u8>,
-    broadcast_address: String,
+    service_port: Arc<AtomicU16>,
+    service_name: Vec<u8>,
+    lease: Option<Duration>,
+    priority: u16,
+    weight: u16,
+    generation: Option<u32>,
+    advertised_ip: Option<IpAddr>,
+    hostname: Option<String>,
+    metadata: Arc<Mutex<HashMap<String, String>>>,
+    sequence: Arc<AtomicU32>,
+    timestamped: bool,
+    is_ready: Option<Arc<dyn Fn() -> bool + Send + Sync>>,
+    on_network_change: Option<interfaces::NetworkChangeCallback>,
+    label: Option<String>,
+    broadcast_address: SocketAddr,
+    destinations: Vec<SocketAddr>,
+    magic_number: u16,
+    #[cfg(feature = "compression")]
+    compressed: bool,
+    #[cfg(feature = "signed-beacons")]
+    signing_key: Option<Vec<u8>>,
+    counters: Arc<SenderCounters>,
+}
+
+/// `SenderStats` is a snapshot of the counters of a `BeaconSender`, see `BeaconSender::stats()`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SenderStats {
+    /// The number of beacons sent
+    pub sent: u64,
+    /// The number of beacons that could not be sent
+    pub send_errors: u64,
+}
+
+/// `SendReport` is where a beacon was sent and how many bytes of it, see
+/// `BeaconSender::send_one_beacon()` and `BeaconSender::announce_to()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SendReport {
+    /// The number of bytes sent
+    pub bytes: usize,
+    /// The address the beacon was sent to
+    pub destination: SocketAddr,
+}
+
+impl std::fmt::Display for SendReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} bytes to {}", self.bytes, self.destination)
+    }
+}
+
+#[derive(Default)]
+struct SenderCounters {
+    sent: AtomicU64,
+    send_errors: AtomicU64,
 }
 
 fn u16_to_array_of_u8(x:u16) -> [u8;2] {
@@ -98,154 +290,3690 @@ fn array_of_u8_to_u16(array: &[u8]) -> u16 {
     upper + lower
 }
 
+fn log_prefix(label: &Option<String>) -> String {
+    label.as_ref().map(|label| format!("[{label}] ")).unwrap_or_default()
+}
+
+/*
+    Parse the entries of a metadata field, or None if they are truncated
+ */
+fn parse_metadata(mut value: &[u8]) -> Option<HashMap<String, String>> {
+    let mut metadata = HashMap::new();
+    while !value.is_empty() {
+        let key_end = 2 + array_of_u8_to_u16(value.get(..2)?) as usize;
+        let key = String::from_utf8_lossy(value.get(2..key_end)?).into_owned();
+        let value_start = key_end + 2;
+        let value_end = value_start + array_of_u8_to_u16(value.get(key_end..value_start)?) as usize;
+        let entry_value = String::from_utf8_lossy(value.get(value_start..value_end)?).into_owned();
+        metadata.insert(key, entry_value);
+        value = &value[value_end..];
+    }
+    Some(metadata)
+}
+
+/*
+    Append the checksum of the payload so far, completing it
+ */
+fn append_checksum(payload: &mut Vec<u8>) {
+    let checksum = fletcher16(payload);
+    payload.extend_from_slice(&u16_to_array_of_u8(checksum));
+}
+
+fn fletcher16(data: &[u8]) -> u16 {
+    let (mut sum1, mut sum2) = (0u16, 0u16);
+    for byte in data {
+        sum1 = (sum1 + *byte as u16) % 255;
+        sum2 = (sum2 + sum1) % 255;
+    }
+    (sum2 << 8) | sum1
+}
+
+/*
+    Create a UDP socket bound to `address` that other sockets can also bind to, if they too
+    ask for address reuse
+ */
+fn reusable_socket(address: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = socket2::Socket::new(socket2::Domain::for_address(address), socket2::Type::DGRAM,
+                                      Some(socket2::Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))))]
+    socket.set_reuse_port(true)?;
+    socket.bind(&address.into())?;
+    Ok(socket.into())
+}
+
+/*
+    Check a beacon is not too large for listeners to receive whole
+ */
+fn check_payload_size(payload: &[u8]) -> Result<()> {
+    if payload.len() > MAX_INCOMING_BEACON_SIZE {
+        return Err(DiscoveryError::PayloadTooLarge { size: payload.len(), max: MAX_INCOMING_BEACON_SIZE });
+    }
+    Ok(())
+}
+
+fn append_field(payload: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    payload.push(tag);
+    payload.extend_from_slice(&u16_to_array_of_u8(value.len() as u16));
+    payload.extend_from_slice(value);
+}
+
+fn append_ip_field(payload: &mut Vec<u8>, tag: u8, ip: IpAddr) {
+    match ip {
+        IpAddr::V4(ip) => append_field(payload, tag, &ip.octets()),
+        IpAddr::V6(ip) => append_field(payload, tag, &ip.octets()),
+    }
+}
+
 impl BeaconSender {
     /// Create a new `BeaconSender` to send `Beacon`s for a service with name `service_name` that
-    /// should be contacted on the port `service_port`
-    pub fn new(service_port: u16, service_name: &[u8], broadcast_port: u16) -> io::Result<Self> {
-        // Setting the port to non-zero (or at least the same port used in listener) causes
-        // this to fail. I am not sure of the correct value to use. Docs on UDP says '0' is
-        // permitted, if you do not expect a response from the UDP Datagram sent.
-        let bind_address = format!("{LISTENING_ADDRESS}:0");
-        let socket:UdpSocket = UdpSocket::bind(&bind_address)
-            .map_err(|e|
-                         io::Error::new(io::ErrorKind::AddrInUse,
-                                        format!("SimpDiscover::BeaconSender could not bind to UdpSocket {bind_address} ({e})")))?;
-        info!("Socket bound to: {}", bind_address);
+    /// should be contacted on the port `service_port`. The service name can be at most
+    /// `MAX_SERVICE_NAME_LENGTH` bytes long, and the service port can't be `0`
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, DiscoveryError, MAX_SERVICE_NAME_LENGTH};
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let too_long = vec![b'x'; 2000];
+    /// match BeaconSender::new(8080, &too_long, broadcast_port) {
+    ///     Err(DiscoveryError::PayloadTooLarge { .. }) => {},
+    ///     _ => panic!("Expected a PayloadTooLarge error"),
+    /// }
+    ///
+    /// let longest = vec![b'x'; MAX_SERVICE_NAME_LENGTH];
+    /// assert!(BeaconSender::new(8080, &longest, broadcast_port).is_ok());
+    ///
+    /// match BeaconSender::new(0, "unset".as_bytes(), broadcast_port) {
+    ///     Err(DiscoveryError::ZeroServicePort) => {},
+    ///     _ => panic!("Expected a ZeroServicePort error"),
+    /// }
+    /// ```
+    pub fn new(service_port: u16, service_name: &[u8], broadcast_port: u16) -> Result<Self> {
+        Self::builder()
+            .service_port(service_port)
+            .service_name(service_name)
+            .broadcast_port(broadcast_port)
+            .build()
+    }
 
-        socket.set_broadcast(true)?;
-        info!("Broadcast mode set to ON");
+    /// Create a new `BeaconSender` like `new()`, for the common case of a service name that is
+    /// a string
+    ///
+    /// ```
+    /// use simpdiscoverylib::BeaconSender;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let beacon = BeaconSender::new_str(8080, "named", broadcast_port)
+    ///     .expect("Could not create sender");
+    /// assert_eq!(beacon.config().expect("Could not get config").service_name, "named");
+    /// ```
+    pub fn new_str(service_port: u16, service_name: &str, broadcast_port: u16) -> Result<Self> {
+        Self::new(service_port, service_name.as_bytes(), broadcast_port)
+    }
 
-        // Create payload with magic number, service_port number and service_name
-        let mut beacon_payload: Vec<u8> = u16_to_array_of_u8(MAGIC_NUMBER).to_vec();
-        beacon_payload.append(&mut u16_to_array_of_u8(service_port).to_vec());
-        beacon_payload.append(&mut service_name.to_vec());
+    /// Create a `BeaconSenderBuilder` to set the options of a new `BeaconSender` by name
+    pub fn builder() -> BeaconSenderBuilder {
+        BeaconSenderBuilder::new()
+    }
 
-        let broadcast_address = format!("{BROADCAST_ADDRESS}:{broadcast_port}");
+    /// Create a new `BeaconSender` whose beacons are sent from the fixed local port `bind_port`,
+    /// rather than an ephemeral one, e.g. so firewall egress rules can match the source port.
+    /// The socket is bound with `SO_REUSEADDR` (and `SO_REUSEPORT` where supported) so the
+    /// port can be re-bound straight away after a restart, or shared by several senders
+    ///
+    /// ```
+    /// use simpdiscoverylib::BeaconSender;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let bind_port = pick_unused_port().expect("Could not get a free port to bind to");
+    /// let first = BeaconSender::new_with_bind_port(8080, "fixed".as_bytes(), broadcast_port, bind_port)
+    ///     .expect("Could not create sender");
+    /// let second = BeaconSender::new_with_bind_port(8081, "fixed".as_bytes(), broadcast_port, bind_port)
+    ///     .expect("Could not create a second sender on the same port");
+    /// assert_eq!(first.config().expect("No config").local_address.port(), bind_port);
+    /// assert_eq!(second.config().expect("No config").local_address.port(), bind_port);
+    /// first.send_one_beacon().expect("Could not send beacon");
+    /// second.send_one_beacon().expect("Could not send beacon");
+    /// ```
+    pub fn new_with_bind_port(service_port: u16, service_name: &[u8], broadcast_port: u16,
+                              bind_port: u16) -> Result<Self> {
+        let bind_address = SocketAddr::from((Ipv4Addr::UNSPECIFIED, bind_port));
+        let socket = reusable_socket(bind_address)
+            .map_err(|source| DiscoveryError::Bind { address: bind_address.to_string(), source })?;
+        info!("Socket bound with address reuse to: {}", bind_address);
+        let broadcast_address = SocketAddr::from((Ipv4Addr::BROADCAST, broadcast_port));
+        Self::from_socket(socket, service_port, service_name, broadcast_address, true)
+    }
 
-        Ok(Self {
-            socket,
-            beacon_payload,
-            broadcast_address,
-        })
+    /// Create a new `BeaconSender` that sends `Beacon`s to the IPv4 multicast group
+    /// `multicast_address` on `port`, rather than to the broadcast address. Multicast often
+    /// traverses enterprise and cloud networks that filter broadcasts. Listeners must be created
+    /// with `BeaconListener::new_multicast()` using the same group and port.
+    ///
+    /// The OS default multicast TTL is usually `1`, confining beacons to the local network
+    /// segment, so beacons that need to be routed further require the sender to set a higher
+    /// multicast TTL with `set_multicast_ttl()`. Administratively scoped groups in
+    /// `239.255.0.0/16` are a good choice
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::net::Ipv4Addr;
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let group = Ipv4Addr::new(239, 255, 42, 99);
+    /// let port = pick_unused_port().expect("Could not get a free port for multicast");
+    /// let listener = BeaconListener::new_multicast("multicast".as_bytes(), group, port)
+    ///     .expect("Could not create listener");
+    ///
+    /// let beacon = BeaconSender::new_multicast(8080, "multicast".as_bytes(), group, port)
+    ///     .expect("Could not create sender");
+    /// std::thread::spawn(move || {
+    ///     beacon.send_loop(Duration::from_millis(100)).expect("Could not enter send_loop");
+    /// });
+    ///
+    /// let beacon = listener.wait(Some(Duration::from_secs(5))).expect("Failed to receive beacon");
+    /// assert_eq!(beacon.service_port, 8080);
+    /// ```
+    pub fn new_multicast(service_port: u16, service_name: &[u8], multicast_address: Ipv4Addr,
+                         port: u16) -> Result<Self> {
+        if !multicast_address.is_multicast() {
+            return Err(DiscoveryError::InvalidAddress(format!("{multicast_address} is not a multicast address")));
+        }
+        let bind_address = format!("{LISTENING_ADDRESS}:0");
+        let multicast_address = SocketAddr::from((multicast_address, port));
+        Self::bind(&bind_address, service_port, service_name, multicast_address, false)
     }
 
-    /// Enter an infinite loop sending `Beacon`s periodically
-    pub fn send_loop(&self, period: Duration) -> io::Result<()> {
-        loop {
-            self.send_one_beacon()?;
-            std::thread::sleep(period);
+    /// Create a new `BeaconSender` that sends `Beacon`s to the IPv6 multicast group
+    /// `multicast_address` on `port`, for IPv6 networks where there is no broadcast. Listeners
+    /// must be created with `BeaconListener::new_v6()` using the same group and port.
+    ///
+    /// `scope_id` is the index of the interface to send on, which link-local groups such as
+    /// those in `ff02::/16` need when the host has more than one interface, or `0` to let the OS
+    /// choose. The wire format of the beacons is the same as over IPv4
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::net::Ipv6Addr;
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let group: Ipv6Addr = "ff02::beef:1".parse().expect("Invalid address");
+    /// let port = pick_unused_port().expect("Could not get a free port for multicast");
+    /// let listener = BeaconListener::new_v6("six".as_bytes(), group, port, 0)
+    ///     .expect("Could not create listener");
+    ///
+    /// let beacon = BeaconSender::new_v6(8080, "six".as_bytes(), group, port, 0)
+    ///     .expect("Could not create sender");
+    /// assert_eq!(beacon.config().expect("Could not get config").broadcast_address,
+    ///            format!("[ff02::beef:1]:{port}"));
+    /// std::thread::spawn(move || {
+    ///     beacon.send_loop(Duration::from_millis(100)).expect("Could not enter send_loop");
+    /// });
+    ///
+    /// let beacon = listener.wait(Some(Duration::from_secs(5))).expect("Failed to receive beacon");
+    /// assert_eq!(beacon.service_port, 8080);
+    /// assert!(beacon.source_addr.is_ipv6());
+    /// ```
+    pub fn new_v6(service_port: u16, service_name: &[u8], multicast_address: Ipv6Addr, port: u16,
+                  scope_id: u32) -> Result<Self> {
+        if !multicast_address.is_multicast() {
+            return Err(DiscoveryError::InvalidAddress(format!("{multicast_address} is not a multicast address")));
         }
+        let bind_address = format!("[{}]:0", Ipv6Addr::UNSPECIFIED);
+        let multicast_address = SocketAddrV6::new(multicast_address, port, 0, scope_id).into();
+        let sender = Self::bind(&bind_address, service_port, service_name, multicast_address, false)?;
+        socket2::SockRef::from(&sender.socket).set_multicast_if_v6(scope_id)?;
+        Ok(sender)
     }
 
-    /// Send a single `Beacon` out
-    pub fn send_one_beacon(&self) -> io::Result<usize> {
-        trace!("Sending Beacon '{}' to: '{}'", String::from_utf8_lossy(&self.beacon_payload[4..]),
-            self.broadcast_address);
-        self.socket.send_to(&self.beacon_payload, &self.broadcast_address)
+    /// Create a new `BeaconSender` like `new()`, but with its socket bound to exactly the local
+    /// IP and port in `local`, rather than any address and an ephemeral port. Useful where
+    /// firewall rules require a fixed source address and port for beacons.
+    ///
+    /// `local` must be an IPv4 address, as beacons are sent to an IPv4 broadcast address
+    ///
+    /// ```
+    /// use simpdiscoverylib::BeaconSender;
+    /// use std::net::SocketAddr;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let local_port = pick_unused_port().expect("Could not get a free port");
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let local: SocketAddr = format!("0.0.0.0:{local_port}").parse().expect("Invalid address");
+    /// let beacon = BeaconSender::new_bound(local, 8080, "bound".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// assert_eq!(beacon.config().expect("Could not get config").local_address, local);
+    ///
+    /// // The same local address and port cannot be bound twice
+    /// assert!(BeaconSender::new_bound(local, 8080, "bound".as_bytes(), broadcast_port).is_err());
+    /// ```
+    pub fn new_bound(local: SocketAddr, service_port: u16, service_name: &[u8],
+                     broadcast_port: u16) -> Result<Self> {
+        if !local.is_ipv4() {
+            return Err(DiscoveryError::InvalidAddress(format!("cannot broadcast from non IPv4 address {local}")));
+        }
+        let broadcast_address = SocketAddr::from((Ipv4Addr::BROADCAST, broadcast_port));
+        Self::bind(&local.to_string(), service_port, service_name, broadcast_address, true)
     }
-}
 
-/// `Beacon` contains information about the beacon that was received by a `BeaconListener`
-pub struct Beacon {
-    /// The IP address and port the beacon was sent from
-    pub service_ip: String,
-    /// The port the service is running on
-    pub service_port: u16,
-    /// The name of the service sending the beacon
-    pub service_name: Vec<u8>
-}
+    /// Create a new `BeaconSender` that sends `Beacon`s by unicast to `127.0.0.1` on `port`,
+    /// without broadcast mode, for a `BeaconListener` created with `BeaconListener::new_loopback()`.
+    /// Beacons never leave the host, so tests using it behave the same whatever the broadcast
+    /// behaviour of the OS or CI sandbox they run in
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let port = pick_unused_port().expect("Could not get a free port");
+    /// let listener = BeaconListener::new_loopback("looped".as_bytes(), port)
+    ///     .expect("Could not create listener");
+    /// let beacon = BeaconSender::new_loopback(8080, "looped".as_bytes(), port)
+    ///     .expect("Could not create sender");
+    /// assert!(!beacon.config().expect("Could not get config").broadcast);
+    /// beacon.send_one_beacon().expect("Could not send beacon");
+    ///
+    /// let beacon = listener.wait(Some(Duration::from_secs(5))).expect("Failed to receive beacon");
+    /// assert_eq!(beacon.service_ip, "127.0.0.1");
+    /// assert_eq!(beacon.service_port, 8080);
+    /// ```
+    pub fn new_loopback(service_port: u16, service_name: &[u8], port: u16) -> Result<Self> {
+        let bind_address = format!("{}:0", Ipv4Addr::LOCALHOST);
+        let loopback_address = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        Self::bind(&bind_address, service_port, service_name, loopback_address, false)
+    }
 
-impl std::fmt::Display for Beacon {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let service_name = String::from_utf8(self.service_name.clone()).unwrap_or_else(|_| "Invalid UTF-8 String".into());
-        write!(f, "ServiceName: '{}', Service IP: {}, Service Port: {}", service_name, self.service_ip, self.service_port)
+    fn bind(bind_address: &str, service_port: u16, service_name: &[u8],
+            broadcast_address: SocketAddr, broadcast: bool) -> Result<Self> {
+        let socket:UdpSocket = UdpSocket::bind(bind_address)
+            .map_err(|source| DiscoveryError::Bind { address: bind_address.to_string(), source })?;
+        info!("Socket bound to: {}", bind_address);
+        Self::from_socket(socket, service_port, service_name, broadcast_address, broadcast)
     }
-}
 
-/// `BeaconListener` listens for new `Beacons` on the specified port
-///
-/// # Example of using `BeaconListener` with timeout
-/// ```
-/// use simpdiscoverylib::BeaconListener;
-/// use std::time::Duration;
-/// use portpicker::pick_unused_port;
-///
-/// let listening_port = pick_unused_port().expect("Could not get a free port to listen on");
-/// let listener = BeaconListener::new("_my_service._tcp.local".as_bytes(), listening_port)
-///     .expect("Could not create listener");
-///
-/// // Avoid blocking tests by setting a short timeout, expect an error, as there is no sender setup
-/// assert!(listener.wait(Some(Duration::from_millis(1))).is_err());
-/// ```
-pub struct BeaconListener {
-    socket: UdpSocket,
-    service_name: Vec<u8>,
-}
+    fn from_socket(socket: UdpSocket, service_port: u16, service_name: &[u8],
+                   broadcast_address: SocketAddr, broadcast: bool) -> Result<Self> {
+        // A beacon advertising port 0 is a bug, that would only surface as connections refused
+        if service_port == 0 {
+            return Err(DiscoveryError::ZeroServicePort);
+        }
 
-impl BeaconListener {
-    /// Create a new `BeaconListener` on `port` with an option `filter` to be applied to incoming
-    /// beacons. This binds to address "0.0.0.0:listening_port"
-    pub fn new(service_name: &[u8], listening_port: u16) -> io::Result<Self> {
-        let listening_address = format!("{}:{}", LISTENING_ADDRESS, listening_port);
-        let socket = UdpSocket::bind(&listening_address)
-            .map_err(|e|
-                io::Error::new(io::ErrorKind::AddrInUse,
-                               format!("SimpDiscover::BeaconListener could not bind to UdpSocket at {listening_address} ({e})")))?;
-        trace!("Socket bound to: {}", listening_address);
-        socket.set_broadcast(true)?;
+        if broadcast {
+            socket.set_broadcast(true).map_err(DiscoveryError::Broadcast)?;
+            info!("Broadcast mode set to ON");
+        }
 
-        Ok(Self {
+        let sender = Self {
             socket,
+            service_port: Arc::new(AtomicU16::new(service_port)),
             service_name: service_name.to_vec(),
-        })
+            lease: None,
+            priority: 0,
+            weight: 0,
+            generation: None,
+            advertised_ip: None,
+            hostname: None,
+            metadata: Arc::new(Mutex::new(HashMap::new())),
+            sequence: Arc::new(AtomicU32::new(0)),
+            timestamped: false,
+            is_ready: None,
+            on_network_change: None,
+            label: None,
+            broadcast_address,
+            destinations: vec![],
+            magic_number: MAGIC_NUMBER,
+            #[cfg(feature = "compression")]
+            compressed: false,
+            #[cfg(feature = "signed-beacons")]
+            signing_key: None,
+            counters: Arc::new(SenderCounters::default()),
+        };
+
+        // Check the beacon fits now, rather than failing on every send
+        check_payload_size(&sender.beacon_payload())?;
+        Ok(sender)
     }
 
-    /// Wait for a `Beacon` on the port specified in `BeaconListener::new()`
-    /// If `timeout` is None, then it will block forever waiting for a beacon matching the optional
-    /// filter (if supplied) in `BeaconListener::new()`. If no `filter` was supplied it will block
-    /// waiting for any beacon to be received.
+    /// Set the IP time-to-live (hop limit) of the beacons sent, which the OS may default to `1`,
+    /// confining them to the local network segment.
     ///
-    /// If `timeout` is `Some(Duration)` then it will block for that duration on the reception of
-    /// each beacon. If the beacon does not match a supplied `filter` then it will loop (blocking
-    /// for `duration` each time until a matching beacon is found.
-    pub fn wait(&self, timeout: Option<Duration>) -> io::Result<Beacon> {
-        self.socket.set_read_timeout(timeout)?;
-        info!("Read timeout set to: {:?}", timeout);
+    /// Raising it lets beacons be routed beyond the local link, advertising the service to
+    /// every network they reach, so only do so deliberately on networks you trust
+    ///
+    /// ```
+    /// use simpdiscoverylib::BeaconSender;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let beacon = BeaconSender::new(8080, "routed".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// beacon.set_ttl(4).expect("Could not set TTL");
+    /// assert_eq!(beacon.config().expect("Could not get config").ttl, 4);
+    /// ```
+    pub fn set_ttl(&self, ttl: u32) -> Result<()> {
+        self.socket.set_ttl(ttl)?;
+        info!("{}TTL set to: {}", self.log_prefix(), ttl);
+        Ok(())
+    }
 
-        info!("Waiting for beacon matching '{}'", String::from_utf8_lossy(&self.service_name));
-        loop {
-            let beacon = self.receive_one_beacon()?;
+    /// Set the time-to-live (hop limit) of beacons sent to a multicast group by a sender created
+    /// with `new_multicast()`. The OS default is usually `1`, confining them to the local network
+    /// segment. As with `set_ttl()`, raising it advertises the service beyond the local link, so
+    /// only do so deliberately
+    ///
+    /// ```
+    /// use simpdiscoverylib::BeaconSender;
+    /// use std::net::Ipv4Addr;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let port = pick_unused_port().expect("Could not get a free port for multicast");
+    /// let beacon = BeaconSender::new_multicast(8080, "routed".as_bytes(), Ipv4Addr::new(239, 255, 42, 99), port)
+    ///     .expect("Could not create sender");
+    /// beacon.set_multicast_ttl(4).expect("Could not set multicast TTL");
+    /// ```
+    pub fn set_multicast_ttl(&self, ttl: u32) -> Result<()> {
+        self.socket.set_multicast_ttl_v4(ttl)?;
+        info!("{}Multicast TTL set to: {}", self.log_prefix(), ttl);
+        Ok(())
+    }
 
-            if beacon.service_name == self.service_name {
-                trace!("Beacon '{}' matches filter '{}': returning beacon",
-                    String::from_utf8_lossy(&beacon.service_name), String::from_utf8_lossy(&self.service_name));
-                return Ok(beacon);
-            } else {
-                trace!("Beacon '{}' does not match filter '{}': ignoring",
-                    String::from_utf8_lossy(&beacon.service_name), String::from_utf8_lossy(&self.service_name));
-            }
+    /// Set the size of the socket's send buffer (`SO_SNDBUF`), e.g. for a sender announcing
+    /// beacons to many destinations at once that would otherwise fill the OS default and fail
+    /// with `DiscoveryError::Send`. 256 KiB to 1 MiB suits most busy LANs. The OS may round the
+    /// size, and caps it (on Linux at `net.core.wmem_max`)
+    ///
+    /// ```
+    /// use simpdiscoverylib::BeaconSender;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let beacon = BeaconSender::new(8080, "buffered".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// beacon.set_send_buffer_size(256 * 1024).expect("Could not set send buffer size");
+    /// ```
+    pub fn set_send_buffer_size(&self, bytes: usize) -> Result<()> {
+        socket2::SockRef::from(&self.socket).set_send_buffer_size(bytes)?;
+        info!("{}Send buffer size set to: {}", self.log_prefix(), bytes);
+        Ok(())
+    }
+
+    /// Advertise in each `Beacon` that the service information it contains is valid for `lease`,
+    /// independently of how often beacons are sent. Listeners receive it as `Beacon::lease`.
+    /// The lease is sent with millisecond precision
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let beacon = BeaconSender::new(8080, "leased".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender")
+    ///     .with_lease(Duration::from_secs(60));
+    /// std::thread::spawn(move || {
+    ///     beacon.send_loop(Duration::from_millis(100)).expect("Could not enter send_loop");
+    /// });
+    ///
+    /// let listener = BeaconListener::new("leased".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// let beacon = listener.wait(None).expect("Failed to receive beacon");
+    /// assert_eq!(beacon.lease, Some(Duration::from_secs(60)));
+    /// ```
+    pub fn with_lease(mut self, lease: Duration) -> Self {
+        self.lease = Some(lease);
+        self
+    }
+
+    /// Advertise a `priority` for this instance of the service. As with DNS SRV records, clients
+    /// (e.g. using `select_instance()`) should use instances with the lowest priority first.
+    /// Defaults to `0`
+    pub fn with_priority(mut self, priority: u16) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Advertise a `weight` for this instance of the service. As with DNS SRV records, clients
+    /// (e.g. using `select_instance()`) should select between instances with the same priority
+    /// with a probability proportional to their weight. Defaults to `0`
+    pub fn with_weight(mut self, weight: u16) -> Self {
+        self.weight = weight;
+        self
+    }
+
+    /// Tag each `Beacon` with the `generation` (or epoch) of the deployment sending it, surfaced to
+    /// listeners as `Beacon::generation`. During a rolling or blue/green deployment this lets
+    /// listeners prefer beacons from the latest generation and ignore stragglers from a previous one
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let beacon = BeaconSender::new(8080, "generational".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender")
+    ///     .with_generation(42);
+    /// std::thread::spawn(move || {
+    ///     beacon.send_loop(Duration::from_millis(100)).expect("Could not enter send_loop");
+    /// });
+    ///
+    /// let listener = BeaconListener::new("generational".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// let beacon = listener.wait(None).expect("Failed to receive beacon");
+    /// assert_eq!(beacon.generation, Some(42));
+    /// ```
+    pub fn with_generation(mut self, generation: u32) -> Self {
+        self.generation = Some(generation);
+        self
+    }
+
+    /// Attach key/value `metadata` (e.g. `version=1.2.3`) to each `Beacon`, surfaced to listeners
+    /// as `Beacon::metadata`, so they can choose between instances without connecting to them.
+    /// Fails with `DiscoveryError::PayloadTooLarge` if the beacon would be too large for listeners
+    /// to receive, as they drop beacons larger than their receive buffer, so keep it small (or
+    /// call `with_compression()` first, to check the size of the compressed beacon)
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::collections::HashMap;
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let mut metadata = HashMap::new();
+    /// metadata.insert("version".to_string(), "1.2.3".to_string());
+    /// let beacon = BeaconSender::new(8080, "described".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender")
+    ///     .with_metadata(metadata)
+    ///     .expect("Metadata is too large");
+    /// std::thread::spawn(move || {
+    ///     beacon.send_loop(Duration::from_millis(100)).expect("Could not enter send_loop");
+    /// });
+    ///
+    /// let listener = BeaconListener::new("described".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// let beacon = listener.wait(None).expect("Failed to receive beacon");
+    /// assert_eq!(beacon.metadata["version"], "1.2.3");
+    /// ```
+    pub fn with_metadata(self, metadata: HashMap<String, String>) -> Result<Self> {
+        self.update_metadata(metadata)?;
+        Ok(self)
+    }
+
+    /// Change the port advertised for the service, e.g. after it has re-bound to a new port.
+    /// Beacons sent from now on (including by a `send_loop()` running in another thread)
+    /// advertise the new port, without having to create a new sender. Fails with
+    /// `DiscoveryError::ZeroServicePort`, keeping the port advertised, if `service_port` is `0`
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener, DiscoveryError};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let listener = BeaconListener::new("moving".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// let beacon = BeaconSender::new(8080, "moving".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    ///
+    /// beacon.update_service_port(8081).expect("Could not update service port");
+    /// assert!(matches!(beacon.update_service_port(0), Err(DiscoveryError::ZeroServicePort)));
+    /// beacon.send_one_beacon().expect("Could not send beacon");
+    /// let received = listener.wait(Some(Duration::from_secs(5))).expect("Failed to receive beacon");
+    /// assert_eq!(received.service_port, 8081);
+    /// ```
+    pub fn update_service_port(&self, service_port: u16) -> Result<()> {
+        if service_port == 0 {
+            return Err(DiscoveryError::ZeroServicePort);
         }
+        self.service_port.store(service_port, Ordering::Relaxed);
+        info!("{}Service port updated to: {}", self.log_prefix(), service_port);
+        Ok(())
+    }
+
+    /// Replace the metadata attached to beacons sent from now on, like `with_metadata()` but
+    /// while the sender is in use (e.g. by a `send_loop()` running in another thread).
+    /// Fails with `DiscoveryError::PayloadTooLarge`, leaving the metadata unchanged, if the
+    /// beacon would be too large for listeners to receive
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, DiscoveryError};
+    /// use std::collections::HashMap;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let beacon = BeaconSender::new(8080, "redescribed".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    ///
+    /// let mut metadata = HashMap::new();
+    /// metadata.insert("load".to_string(), "0.5".to_string());
+    /// beacon.update_metadata(metadata).expect("Could not update metadata");
+    /// assert_eq!(beacon.config().expect("Could not get config").metadata["load"], "0.5");
+    ///
+    /// let mut too_large = HashMap::new();
+    /// too_large.insert("padding".to_string(), "x".repeat(2000));
+    /// match beacon.update_metadata(too_large) {
+    ///     Err(DiscoveryError::PayloadTooLarge { .. }) => {},
+    ///     _ => panic!("Expected a PayloadTooLarge error"),
+    /// }
+    /// assert_eq!(beacon.config().expect("Could not get config").metadata["load"], "0.5");
+    /// ```
+    pub fn update_metadata(&self, metadata: HashMap<String, String>) -> Result<()> {
+        let mut current = self.lock_metadata();
+        check_payload_size(&self.payload_with(self.sequence.load(Ordering::Relaxed), &metadata, false))?;
+        *current = metadata;
+        info!("{}Metadata updated", self.log_prefix());
+        Ok(())
+    }
+
+    fn lock_metadata(&self) -> MutexGuard<'_, HashMap<String, String>> {
+        self.metadata.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Include the time each `Beacon` is sent (in Unix epoch milliseconds), surfaced to listeners
+    /// as `Beacon::sent_at`, so they can use `Beacon::age()` to discard stale beacons.
+    /// This depends on the clocks of the sender and listeners being roughly in sync
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let beacon = BeaconSender::new(8080, "timestamped".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender")
+    ///     .with_timestamps();
+    /// std::thread::spawn(move || {
+    ///     beacon.send_loop(Duration::from_millis(100)).expect("Could not enter send_loop");
+    /// });
+    ///
+    /// let listener = BeaconListener::new("timestamped".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// let beacon = listener.wait(None).expect("Failed to receive beacon");
+    /// assert!(beacon.sent_at.is_some());
+    /// assert!(beacon.age().expect("Beacon from the future") < Duration::from_secs(5));
+    /// ```
+    pub fn with_timestamps(mut self) -> Self {
+        self.timestamped = true;
+        self
+    }
+
+    /// Include the name of this host in the beacons, so listeners can show services by a
+    /// human-readable name alongside their IP addresses. Any invalid UTF-8 in the host name is
+    /// replaced by `U+FFFD`. Fails with `DiscoveryError::Io` if the host name can't be read
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let listener = BeaconListener::new("named-host".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// BeaconSender::new(8080, "named-host".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender")
+    ///     .with_hostname().expect("Could not get the host name")
+    ///     .send_one_beacon().expect("Could not send beacon");
+    ///
+    /// let beacon = listener.wait(Some(Duration::from_secs(5))).expect("Failed to receive beacon");
+    /// let hostname = hostname::get().expect("Could not get the host name");
+    /// assert_eq!(beacon.hostname, Some(hostname.to_string_lossy().into_owned()));
+    /// ```
+    pub fn with_hostname(mut self) -> Result<Self> {
+        self.hostname = Some(hostname::get()?.to_string_lossy().into_owned());
+        Ok(self)
+    }
+
+    /// Start the beacons with `magic_number` instead of the default `0xbeef`, so that they are
+    /// only received by listeners set to the same magic number with
+    /// `BeaconListener::with_magic_number()`. Giving each application its own magic number stops
+    /// unrelated applications on the same network from parsing each other's beacons
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let listener = BeaconListener::new("namespaced".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener")
+    ///     .with_magic_number(0xcafe);
+    ///
+    /// BeaconSender::new(8080, "namespaced".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender")
+    ///     .send_one_beacon().expect("Could not send beacon");
+    /// let beacon = BeaconSender::new(8081, "namespaced".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender")
+    ///     .with_magic_number(0xcafe);
+    /// assert_eq!(beacon.config().expect("Could not get config").magic_number, 0xcafe);
+    /// beacon.send_one_beacon().expect("Could not send beacon");
+    ///
+    /// let beacon = listener.wait(Some(Duration::from_secs(5))).expect("Failed to receive beacon");
+    /// assert_eq!(beacon.service_port, 8081);
+    /// assert_eq!(listener.take_stats().dropped_invalid, 1);
+    /// ```
+    pub fn with_magic_number(mut self, magic_number: u16) -> Self {
+        self.magic_number = magic_number;
+        self
+    }
+
+    /// Advertise `ip` as the address the service should be contacted at. Listeners use it for
+    /// `Beacon::service_ip` instead of the source address of the beacon, which behind NAT or
+    /// across bridged segments is not the address the service is reachable at
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::net::{IpAddr, Ipv4Addr};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let beacon = BeaconSender::new(8080, "natted".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender")
+    ///     .with_advertised_ip(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7)));
+    /// std::thread::spawn(move || {
+    ///     beacon.send_loop(Duration::from_millis(100)).expect("Could not enter send_loop");
+    /// });
+    ///
+    /// let listener = BeaconListener::new("natted".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// let beacon = listener.wait(None).expect("Failed to receive beacon");
+    /// assert_eq!(beacon.service_ip, "203.0.113.7");
+    /// ```
+    pub fn with_advertised_ip(mut self, ip: IpAddr) -> Self {
+        self.advertised_ip = Some(ip);
+        self
+    }
+
+    /// Don't let `send_loop()` send any beacons until `is_ready` returns `true`, so that a service
+    /// that needs to warm up is not discovered (and connected to) before it can actually serve.
+    /// `is_ready` is checked before every beacon would be sent, so beacons also stop if the
+    /// service becomes not ready again
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::sync::Arc;
+    /// use std::sync::atomic::{AtomicBool, Ordering};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let ready = Arc::new(AtomicBool::new(false));
+    /// let ready_check = ready.clone();
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let beacon = BeaconSender::new(8080, "warming_up".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender")
+    ///     .with_readiness_check(move || ready_check.load(Ordering::SeqCst));
+    /// std::thread::spawn(move || {
+    ///     beacon.send_loop(Duration::from_millis(50)).expect("Could not enter send_loop");
+    /// });
+    ///
+    /// let listener = BeaconListener::new("warming_up".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// assert!(listener.wait(Some(Duration::from_millis(300))).is_err());
+    ///
+    /// ready.store(true, Ordering::SeqCst);
+    /// assert!(listener.wait(Some(Duration::from_secs(5))).is_ok());
+    /// ```
+    pub fn with_readiness_check<F>(mut self, is_ready: F) -> Self
+        where F: Fn() -> bool + Send + Sync + 'static {
+        self.is_ready = Some(Arc::new(is_ready));
+        self
+    }
+
+    /// Include `label` in this sender's log lines, e.g. `[printer-announcer] Sending Beacon ...`,
+    /// to tell apart the logs of several senders in one process
+    pub fn with_label(mut self, label: &str) -> Self {
+        self.label = Some(label.to_string());
+        self
+    }
+
+    fn log_prefix(&self) -> String {
+        log_prefix(&self.label)
+    }
+
+    /// The socket the sender sends beacons from, e.g. to set options the crate doesn't expose
+    /// such as the type of service. Misconfiguring the socket can break discovery: the sender
+    /// expects it to stay bound, with broadcast enabled when sending to a broadcast address
+    ///
+    /// ```
+    /// use simpdiscoverylib::BeaconSender;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let beacon = BeaconSender::new(8080, "tuned".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// beacon.socket().set_ttl(2).expect("Could not set TTL");
+    /// assert_eq!(beacon.socket().ttl().expect("Could not get TTL"), 2);
+    /// ```
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// Get a `SenderConfig` with everything this `BeaconSender` has been configured with, to help
+    /// spot misconfigurations (such as a wrong broadcast address) when discovery isn't working.
+    /// With the `serde` feature enabled it can be serialized, e.g. to JSON, to include in reports
+    ///
+    /// ```
+    /// use simpdiscoverylib::BeaconSender;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let beacon = BeaconSender::new(8080, "configured".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// let config = beacon.config().expect("Could not get sender config");
+    /// assert_eq!(config.broadcast_address, format!("255.255.255.255:{broadcast_port}"));
+    /// assert_eq!(config.service_name, "configured");
+    /// assert!(config.broadcast);
+    /// ```
+    pub fn config(&self) -> Result<SenderConfig> {
+        Ok(SenderConfig {
+            local_address: self.socket.local_addr()?,
+            broadcast_address: self.broadcast_address.to_string(),
+            destinations: self.destinations.iter().map(ToString::to_string).collect(),
+            broadcast: self.socket.broadcast()?,
+            ttl: self.socket.ttl()?,
+            magic_number: self.magic_number,
+            protocol_version: PROTOCOL_VERSION,
+            service_port: self.service_port.load(Ordering::Relaxed),
+            service_name: String::from_utf8_lossy(&self.service_name).into_owned(),
+            lease: self.lease,
+            priority: self.priority,
+            weight: self.weight,
+            generation: self.generation,
+            advertised_ip: self.advertised_ip,
+            hostname: self.hostname.clone(),
+            metadata: self.lock_metadata().clone(),
+            label: self.label.clone(),
+        })
     }
 
     /*
-        Receive one beacon
+        Create the payload of the next beacon to send, moving on the sequence number
      */
-    fn receive_one_beacon(&self) -> io::Result<Beacon> {
-        let mut buffer = [0; MAX_INCOMING_BEACON_SIZE];
+    pub(crate) fn next_beacon_payload(&self) -> Vec<u8> {
+        self.payload_with_sequence(self.sequence.fetch_add(1, Ordering::Relaxed))
+    }
 
-        loop {
-            let (number_of_bytes, source_address) = self.socket.recv_from(&mut buffer)?;
-            let magic_number = array_of_u8_to_u16(&buffer[0..2]);
-            if magic_number == MAGIC_NUMBER {
-                let service_port = array_of_u8_to_u16(&buffer[2..4]);
-                let service_name = buffer[4..number_of_bytes].to_vec();
-
-                return Ok(Beacon {
-                    service_ip: source_address.ip().to_string(),
-                    service_port,
-                    service_name
-                });
+    /*
+        Create the payload of the next beacon to send, without moving on the sequence number
+     */
+    pub(crate) fn beacon_payload(&self) -> Vec<u8> {
+        self.payload_with_sequence(self.sequence.load(Ordering::Relaxed))
+    }
+
+    /*
+        Create payload with magic number, service_port number, service_name and optional fields
+     */
+    fn payload_with_sequence(&self, sequence: u32) -> Vec<u8> {
+        self.payload_with(sequence, &self.lock_metadata(), false)
+    }
+
+    fn payload_with(&self, sequence: u32, metadata: &HashMap<String, String>, goodbye: bool) -> Vec<u8> {
+        let mut payload: Vec<u8> = u16_to_array_of_u8(self.magic_number).to_vec();
+        payload.push(PROTOCOL_VERSION);
+        payload.extend_from_slice(&u16_to_array_of_u8(self.service_port.load(Ordering::Relaxed)));
+        payload.extend_from_slice(&u16_to_array_of_u8(self.service_name.len() as u16));
+        payload.extend_from_slice(&self.service_name);
+        #[cfg(feature = "compression")]
+        let fields_start = payload.len();
+
+        if let Some(lease) = self.lease {
+            let millis = u32::try_from(lease.as_millis()).unwrap_or(u32::MAX);
+            append_field(&mut payload, LEASE_FIELD, &millis.to_be_bytes());
+        }
+
+        if self.priority != 0 {
+            append_field(&mut payload, PRIORITY_FIELD, &u16_to_array_of_u8(self.priority));
+        }
+
+        if self.weight != 0 {
+            append_field(&mut payload, WEIGHT_FIELD, &u16_to_array_of_u8(self.weight));
+        }
+
+        if let Some(generation) = self.generation {
+            append_field(&mut payload, GENERATION_FIELD, &generation.to_be_bytes());
+        }
+
+        if let Some(ip) = self.advertised_ip {
+            append_ip_field(&mut payload, ADVERTISED_IP_FIELD, ip);
+        }
+
+        if let Some(hostname) = &self.hostname {
+            append_field(&mut payload, HOSTNAME_FIELD, hostname.as_bytes());
+        }
+
+        if !metadata.is_empty() {
+            // Sorted so the same metadata always produces the same payload
+            let mut entries: Vec<(&String, &String)> = metadata.iter().collect();
+            entries.sort();
+            let mut value = vec![];
+            for (key, entry_value) in entries {
+                value.extend_from_slice(&u16_to_array_of_u8(key.len() as u16));
+                value.extend_from_slice(key.as_bytes());
+                value.extend_from_slice(&u16_to_array_of_u8(entry_value.len() as u16));
+                value.extend_from_slice(entry_value.as_bytes());
             }
+            append_field(&mut payload, METADATA_FIELD, &value);
+        }
+
+        if self.timestamped {
+            let millis = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+            append_field(&mut payload, SENT_AT_FIELD, &u64::try_from(millis).unwrap_or(u64::MAX).to_be_bytes());
+        }
+
+        if goodbye {
+            append_field(&mut payload, GOODBYE_FIELD, &[]);
+        }
+
+        append_field(&mut payload, SEQUENCE_FIELD, &sequence.to_be_bytes());
+        #[cfg(feature = "compression")]
+        if self.compressed {
+            compression::compress_fields(&mut payload, fields_start);
+        }
+        #[cfg(feature = "signed-beacons")]
+        if let Some(key) = &self.signing_key {
+            signing::append_signature(&mut payload, key);
+        }
+        append_checksum(&mut payload);
+        payload
+    }
+
+    /// Enter an infinite loop sending `Beacon`s periodically, skipping any while a readiness
+    /// check set with `with_readiness_check()` says the service is not ready
+    pub fn send_loop(&self, period: Duration) -> Result<()> {
+        self.send_while(|| true, || period)
+    }
+
+    /// Enter an infinite loop sending `Beacon`s like `send_loop()`, but sleeping for a random
+    /// time within `jitter` either side of `period` between each one. This stops the beacons of
+    /// many hosts started at the same time from staying synchronized and arriving in bursts
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let beacon = BeaconSender::new(8080, "jittered".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// std::thread::spawn(move || {
+    ///     beacon.send_loop_with_jitter(Duration::from_millis(100), Duration::from_millis(50))
+    ///         .expect("Could not enter send_loop_with_jitter");
+    /// });
+    ///
+    /// let listener = BeaconListener::new("jittered".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// let beacon = listener.wait(Some(Duration::from_secs(5))).expect("Failed to receive beacon");
+    /// assert_eq!(beacon.service_port, 8080);
+    /// ```
+    pub fn send_loop_with_jitter(&self, period: Duration, jitter: Duration) -> Result<()> {
+        self.send_while(|| true, || jittered(period, jitter, random_u64()))
+    }
+
+    /// Send `Beacon`s periodically like `send_loop()`, until `stop` is set to `true`, when it
+    /// returns `Ok(())`. `stop` is checked between sends, so it may take up to `period` to return.
+    /// This lets a background sending thread be shut down cleanly without exiting the process
+    ///
+    /// ```
+    /// use simpdiscoverylib::BeaconSender;
+    /// use std::sync::Arc;
+    /// use std::sync::atomic::{AtomicBool, Ordering};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let beacon = BeaconSender::new(8080, "stoppable".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// let stop = Arc::new(AtomicBool::new(false));
+    /// let stop_sending = stop.clone();
+    /// let sender = std::thread::spawn(move || {
+    ///     beacon.send_loop_until(Duration::from_millis(10), stop_sending)
+    /// });
+    ///
+    /// stop.store(true, Ordering::SeqCst);
+    /// assert!(sender.join().expect("Sending thread panicked").is_ok());
+    /// ```
+    pub fn send_loop_until(&self, period: Duration, stop: Arc<AtomicBool>) -> Result<()> {
+        self.send_while(|| !stop.load(Ordering::SeqCst), || period)
+    }
+
+    fn send_while<F, P>(&self, keep_sending: F, period: P) -> Result<()>
+        where F: Fn() -> bool, P: Fn() -> Duration {
+        while keep_sending() {
+            if self.ready() {
+                self.send_one_beacon()?;
+            } else {
+                trace!("{}Service '{}' is not ready: not sending beacon", self.log_prefix(), String::from_utf8_lossy(&self.service_name));
+            }
+            std::thread::sleep(period());
+        }
+        Ok(())
+    }
+
+    fn ready(&self) -> bool {
+        self.is_ready.as_ref().is_none_or(|is_ready| is_ready())
+    }
+
+    /// Enter an infinite loop sending `Beacon`s periodically like `send_loop()`, but never
+    /// returning on errors. Each failure to send is logged and retried after a backoff, starting
+    /// at `period` and doubling on each consecutive failure up to `max_backoff`, then back to
+    /// `period` once a beacon is sent. Long lived senders survive transient outages such as the
+    /// network going down or the host sleeping, and failures are counted in `stats()`
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let beacon = BeaconSender::new(8080, "resilient".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// std::thread::spawn(move || {
+    ///     beacon.send_loop_resilient(Duration::from_millis(100), Duration::from_secs(30))
+    /// });
+    ///
+    /// let listener = BeaconListener::new("resilient".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// let beacon = listener.wait(Some(Duration::from_secs(5))).expect("Failed to receive beacon");
+    /// assert_eq!(beacon.service_port, 8080);
+    /// ```
+    pub fn send_loop_resilient(&self, period: Duration, max_backoff: Duration) -> ! {
+        let mut backoff = None;
+        loop {
+            if !self.ready() {
+                trace!("{}Service '{}' is not ready: not sending beacon", self.log_prefix(), String::from_utf8_lossy(&self.service_name));
+            } else if let Err(e) = self.send_one_beacon() {
+                let delay = backoff_after(backoff, period, max_backoff);
+                warn!("{}Could not send beacon ({}): retrying in {:?}", self.log_prefix(), e, delay);
+                backoff = Some(delay);
+                std::thread::sleep(delay);
+                continue;
+            }
+            backoff = None;
+            std::thread::sleep(period);
+        }
+    }
+
+    /// Send a single `Beacon` out to each of the sender's destinations, returning a `SendReport`
+    /// of the bytes sent to each, in the order they were sent: the broadcast (or multicast)
+    /// address first, then those added with `with_destination()`
+    ///
+    /// ```
+    /// use simpdiscoverylib::BeaconSender;
+    /// use std::net::SocketAddr;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let other: SocketAddr = "127.0.0.1:9003".parse().expect("Invalid address");
+    /// let beacon = BeaconSender::new(8080, "reported".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender")
+    ///     .with_destination(other);
+    /// let reports = beacon.send_one_beacon().expect("Could not send beacon");
+    /// assert_eq!(reports.len(), 2);
+    /// assert_eq!(reports[0].destination, format!("255.255.255.255:{broadcast_port}").parse().expect("Invalid address"));
+    /// assert_eq!(reports[1].destination, other);
+    /// assert_eq!(reports[0].bytes, beacon.wire_bytes().len());
+    /// println!("Sent {}", reports[0]);
+    /// ```
+    ///
+    /// # Checking a beacon really leaves the host
+    /// `Ok` only means the OS accepted the datagram. To confirm it was actually broadcast (and
+    /// catch broadcast-mode misconfigurations), bind a second, plain `UdpSocket` to the broadcast
+    /// port and check the exact bytes that are observed
+    /// ```
+    /// use simpdiscoverylib::BeaconSender;
+    /// use std::net::{SocketAddr, UdpSocket};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let capture = UdpSocket::bind(("0.0.0.0", broadcast_port))
+    ///     .expect("Could not bind capture socket");
+    /// capture.set_read_timeout(Some(Duration::from_secs(5))).expect("Could not set timeout");
+    ///
+    /// let beacon = BeaconSender::new(8080, "capture".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// beacon.send_one_beacon().expect("Could not send beacon");
+    ///
+    /// let mut buffer = [0; 1024];
+    /// let (length, _) = capture.recv_from(&mut buffer).expect("No beacon was seen on the wire");
+    /// // magic number, protocol version, service port (8080), service name length, the service name,
+    /// // the sequence number field then the checksum
+    /// let mut expected = vec![0xbe, 0xef, 0x01, 0x1f, 0x90, 0x00, 0x07];
+    /// expected.extend_from_slice(b"capture");
+    /// expected.extend_from_slice(&[0x08, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x66, 0x69]);
+    /// assert_eq!(&buffer[..length], expected.as_slice());
+    /// ```
+    pub fn send_one_beacon(&self) -> Result<Vec<SendReport>> {
+        let payload = self.next_beacon_payload();
+        check_payload_size(&payload)?;
+        self.send_to_all(&payload)
+    }
+
+    /// Send a final `Beacon` flagged as a goodbye (see `Beacon::goodbye`) to every destination,
+    /// when the service is shutting down cleanly. A `ServiceRegistry` that receives it removes the
+    /// service straight away, rather than waiting for its `ttl` to expire, like mDNS goodbye packets
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let listener = BeaconListener::new("departing".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// let beacon = BeaconSender::new(8080, "departing".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// beacon.send_goodbye().expect("Could not send goodbye");
+    ///
+    /// let received = listener.wait(Some(Duration::from_secs(5))).expect("Failed to receive beacon");
+    /// assert!(received.goodbye);
+    /// ```
+    pub fn send_goodbye(&self) -> Result<Vec<SendReport>> {
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        let payload = self.payload_with(sequence, &self.lock_metadata(), true);
+        check_payload_size(&payload)?;
+        self.send_to_all(&payload)
+    }
+
+    /// Also send each `Beacon` to `address`, e.g. the broadcast address of another network on a
+    /// host with several interfaces, so one sender can announce the service on all of them.
+    /// `send_one_beacon()` sends to every destination, returning the first error if any fail
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let first_port = pick_unused_port().expect("Could not get a free port");
+    /// let second_port = pick_unused_port().expect("Could not get a free port");
+    /// let first = BeaconListener::new("everywhere".as_bytes(), first_port).expect("Could not create listener");
+    /// let second = BeaconListener::new("everywhere".as_bytes(), second_port).expect("Could not create listener");
+    ///
+    /// let beacon = BeaconSender::new(8080, "everywhere".as_bytes(), first_port)
+    ///     .expect("Could not create sender")
+    ///     .with_destination(format!("127.0.0.1:{second_port}").parse().expect("Invalid address"));
+    /// assert_eq!(beacon.config().expect("Could not get config").destinations,
+    ///            vec![format!("127.0.0.1:{second_port}")]);
+    /// beacon.send_one_beacon().expect("Could not send beacon");
+    ///
+    /// assert!(first.wait(Some(Duration::from_secs(5))).is_ok());
+    /// assert!(second.wait(Some(Duration::from_secs(5))).is_ok());
+    /// assert_eq!(beacon.stats().sent, 2);
+    /// ```
+    pub fn with_destination(mut self, address: SocketAddr) -> Self {
+        self.destinations.push(address);
+        self
+    }
+
+    /*
+        The broadcast (or multicast) address followed by any other destinations
+     */
+    pub(crate) fn all_destinations(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        std::iter::once(self.broadcast_address).chain(self.destinations.iter().copied())
+    }
+
+    /*
+        Send `payload` to every destination, returning the first error after trying them all
+     */
+    pub(crate) fn send_to_all(&self, payload: &[u8]) -> Result<Vec<SendReport>> {
+        let mut reports = vec![];
+        let mut error = None;
+        for destination in self.all_destinations() {
+            trace!("{}Sending Beacon '{}' to: '{}'", self.log_prefix(), String::from_utf8_lossy(&self.service_name),
+                destination);
+            match self.record_send(self.socket.send_to(payload, destination)) {
+                Ok(bytes) => reports.push(SendReport { bytes, destination }),
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(reports),
+        }
+    }
+
+    /// Get a snapshot of the sender's counters, without resetting them. The counters are
+    /// atomics, so this can be called from another thread while `send_loop()` is running
+    ///
+    /// ```
+    /// use simpdiscoverylib::BeaconSender;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let beacon = BeaconSender::new(8080, "counted".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// beacon.send_one_beacon().expect("Could not send beacon");
+    /// beacon.send_one_beacon().expect("Could not send beacon");
+    ///
+    /// let stats = beacon.stats();
+    /// assert_eq!(stats.sent, 2);
+    /// assert_eq!(stats.send_errors, 0);
+    /// ```
+    pub fn stats(&self) -> SenderStats {
+        SenderStats {
+            sent: self.counters.sent.load(Ordering::Relaxed),
+            send_errors: self.counters.send_errors.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The total number of beacons sent so far, as `stats().sent`. It can be read from another
+    /// thread as a heartbeat, to check that `send_loop()` is still making progress
+    ///
+    /// ```
+    /// use simpdiscoverylib::BeaconSender;
+    /// use std::sync::Arc;
+    /// use std::sync::atomic::{AtomicBool, Ordering};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let beacon = Arc::new(BeaconSender::new(8080, "heartbeat".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender"));
+    /// let stop = Arc::new(AtomicBool::new(false));
+    /// let sender = {
+    ///     let (beacon, stop) = (beacon.clone(), stop.clone());
+    ///     std::thread::spawn(move || beacon.send_loop_until(Duration::from_millis(50), stop))
+    /// };
+    ///
+    /// std::thread::sleep(Duration::from_millis(500));
+    /// stop.store(true, Ordering::SeqCst);
+    /// sender.join().expect("Sending thread panicked").expect("Could not send beacons");
+    /// let sent = beacon.sent_count();
+    /// assert!((5..=12).contains(&sent), "Sent {sent} beacons");
+    /// ```
+    pub fn sent_count(&self) -> u64 {
+        self.counters.sent.load(Ordering::Relaxed)
+    }
+
+    /// Create another handle to this `BeaconSender`, sending from the same socket (cloned with
+    /// `UdpSocket::try_clone()`) and sharing its service port, metadata, sequence number and
+    /// counters, so e.g. one handle can be moved into a thread running `send_loop()` while the
+    /// other is kept to call `send_one_beacon()` or `stats()`. `BeaconSender` is `Send` and `Sync`,
+    /// so it can also be shared by reference or in an `Arc`
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use portpicker::pick_unused_port;
+    ///
+    /// fn assert_send_sync<T: Send + Sync>() {}
+    /// assert_send_sync::<BeaconSender>();
+    /// assert_send_sync::<BeaconListener>();
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let beacon = BeaconSender::new(8080, "cloned".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// let handle = beacon.try_clone().expect("Could not clone sender");
+    /// std::thread::spawn(move || handle.send_one_beacon().expect("Could not send beacon"))
+    ///     .join().expect("Sending thread panicked");
+    /// assert_eq!(beacon.stats().sent, 1);
+    ///
+    /// let listener = BeaconListener::new("cloned".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// beacon.try_clone().expect("Could not clone sender")
+    ///     .send_one_beacon().expect("Could not send beacon");
+    /// let received = listener.wait(None).expect("Failed to receive beacon");
+    /// assert_eq!(received.sequence, 1);
+    /// assert_eq!(beacon.stats().sent, 2);
+    /// ```
+    pub fn try_clone(&self) -> Result<Self> {
+        Ok(Self {
+            socket: self.socket.try_clone()?,
+            service_port: self.service_port.clone(),
+            service_name: self.service_name.clone(),
+            lease: self.lease,
+            priority: self.priority,
+            weight: self.weight,
+            generation: self.generation,
+            advertised_ip: self.advertised_ip,
+            hostname: self.hostname.clone(),
+            metadata: self.metadata.clone(),
+            sequence: self.sequence.clone(),
+            timestamped: self.timestamped,
+            is_ready: self.is_ready.clone(),
+            on_network_change: self.on_network_change.clone(),
+            label: self.label.clone(),
+            broadcast_address: self.broadcast_address,
+            destinations: self.destinations.clone(),
+            magic_number: self.magic_number,
+            #[cfg(feature = "compression")]
+            compressed: self.compressed,
+            #[cfg(feature = "signed-beacons")]
+            signing_key: self.signing_key.clone(),
+            counters: self.counters.clone(),
+        })
+    }
+
+    /*
+        Count the result of sending a beacon, converting any error
+     */
+    pub(crate) fn record_send(&self, result: io::Result<usize>) -> Result<usize> {
+        match result {
+            Ok(length) => {
+                increment(&self.counters.sent);
+                Ok(length)
+            }
+            Err(e) => {
+                increment(&self.counters.send_errors);
+                Err(DiscoveryError::Send(e))
+            }
+        }
+    }
+
+    /// Get the complete datagram that `send_one_beacon()` would transmit, e.g. to hexdump and
+    /// compare against another implementation of the beacon format
+    ///
+    /// ```
+    /// use simpdiscoverylib::BeaconSender;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let beacon = BeaconSender::new(8080, "wire".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender")
+    ///     .with_priority(1);
+    /// assert_eq!(beacon.wire_bytes(), vec![0xbe, 0xef, 0x01, 0x1f, 0x90, 0x00, 0x04, b'w', b'i', b'r', b'e',
+    ///                                      0x02, 0x00, 0x02, 0x00, 0x01,
+    ///                                      0x08, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0xd1, 0x2d]);
+    /// ```
+    pub fn wire_bytes(&self) -> Vec<u8> {
+        self.beacon_payload()
+    }
+
+    /// Send a single `Beacon` as a unicast datagram to just `address`, rather than broadcasting
+    /// it to the whole LAN. Useful for directed announcements, or testing reception at a
+    /// single listener
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let listening_port = pick_unused_port().expect("Could not get a free port to listen on");
+    /// let listener = BeaconListener::new("directed".as_bytes(), listening_port)
+    ///     .expect("Could not create listener");
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let beacon = BeaconSender::new(8080, "directed".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// beacon.announce_to(format!("127.0.0.1:{listening_port}").parse().expect("Invalid address"))
+    ///     .expect("Could not send beacon");
+    ///
+    /// let beacon = listener.wait(Some(Duration::from_secs(5))).expect("Failed to receive beacon");
+    /// assert_eq!(beacon.service_ip, "127.0.0.1");
+    /// ```
+    #[doc(alias = "send_to")]
+    pub fn announce_to(&self, address: SocketAddr) -> Result<SendReport> {
+        trace!("{}Sending Beacon '{}' to: '{}'", self.log_prefix(), String::from_utf8_lossy(&self.service_name), address);
+        let payload = self.next_beacon_payload();
+        check_payload_size(&payload)?;
+        let bytes = self.record_send(self.socket.send_to(&payload, address))?;
+        Ok(SendReport { bytes, destination: address })
+    }
+}
+
+impl Drop for BeaconSender {
+    fn drop(&mut self) {
+        trace!("{}Dropping sender of '{}' to: '{}'", self.log_prefix(), String::from_utf8_lossy(&self.service_name),
+            self.broadcast_address);
+    }
+}
+
+/// `SenderConfig` is a snapshot of how a `BeaconSender` is configured, see `BeaconSender::config()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SenderConfig {
+    /// The local address and port the sender's socket is bound to
+    pub local_address: SocketAddr,
+    /// The address and port beacons are sent to
+    pub broadcast_address: String,
+    /// The other addresses and ports beacons are also sent to, see `BeaconSender::with_destination()`
+    pub destinations: Vec<String>,
+    /// Whether broadcast mode is enabled on the sender's socket
+    pub broadcast: bool,
+    /// The IP time-to-live of sent beacons
+    pub ttl: u32,
+    /// The magic number that starts every beacon
+    pub magic_number: u16,
+    /// The version of the beacon format sent
+    pub protocol_version: u8,
+    /// The port advertised for the service
+    pub service_port: u16,
+    /// The name of the service advertised, lossily converted to UTF-8
+    pub service_name: String,
+    /// The lease advertised in beacons, if any
+    pub lease: Option<Duration>,
+    /// The priority advertised in beacons
+    pub priority: u16,
+    /// The weight advertised in beacons
+    pub weight: u16,
+    /// The deployment generation advertised in beacons, if any
+    pub generation: Option<u32>,
+    /// The IP advertised in beacons for contacting the service, if any
+    pub advertised_ip: Option<IpAddr>,
+    /// The host name included in beacons, if any
+    pub hostname: Option<String>,
+    /// The key/value metadata advertised in beacons
+    pub metadata: HashMap<String, String>,
+    /// The label included in the sender's log lines, if any
+    pub label: Option<String>,
+}
+
+/// `Beacon` contains information about the beacon that was received by a `BeaconListener`.
+///
+/// With the `serde` feature enabled it can be serialized and deserialized, e.g. to JSON to pass
+/// discovered services on to other systems. The service name is serialized as a string if it is
+/// valid UTF-8, and otherwise as `{"base64": "..."}` with the bytes base64 encoded
+///
+/// ```
+/// # #[cfg(feature = "serde")]
+/// # {
+/// use simpdiscoverylib::Beacon;
+///
+/// let beacon = Beacon { service_name: "_my_service._tcp.local".as_bytes().to_vec(), ..Default::default() };
+/// let json = serde_json::to_value(&beacon).expect("Could not serialize beacon");
+/// assert_eq!(json["service_name"], "_my_service._tcp.local");
+/// assert_eq!(serde_json::from_value::<Beacon>(json).expect("Could not deserialize beacon"), beacon);
+///
+/// let binary = Beacon { service_name: vec![0xff, 0x00], ..Default::default() };
+/// let json = serde_json::to_value(&binary).expect("Could not serialize beacon");
+/// assert_eq!(json["service_name"]["base64"], "/wA=");
+/// assert_eq!(serde_json::from_value::<Beacon>(json).expect("Could not deserialize beacon"), binary);
+/// # }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Beacon {
+    /// The version of the beacon format the sender used
+    pub protocol_version: u8,
+    /// The IP address the service can be contacted at: the one the sender advertised if it
+    /// did so, otherwise the IP address the beacon was sent from
+    pub service_ip: String,
+    /// The address and port the beacon was sent from. The port is the sender's (usually
+    /// ephemeral) source port, not the `service_port`
+    pub source_addr: SocketAddr,
+    /// The local address of the interface the beacon was received on, so a listener on a host
+    /// with several interfaces can pick the right one to reply through. Only reported by
+    /// `BeaconListener`s bound to IPv4 addresses, on Linux with the `packet-info` feature
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::net::Ipv4Addr;
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let port = pick_unused_port().expect("Could not get a free port");
+    /// let listener = BeaconListener::new("interfaced".as_bytes(), port).expect("Could not create listener");
+    /// BeaconSender::new_loopback(8080, "interfaced".as_bytes(), port)
+    ///     .expect("Could not create sender")
+    ///     .send_one_beacon().expect("Could not send beacon");
+    ///
+    /// let beacon = listener.wait(Some(Duration::from_secs(5))).expect("Failed to receive beacon");
+    /// if cfg!(all(feature = "packet-info", target_os = "linux")) {
+    ///     assert_eq!(beacon.received_on, Some(Ipv4Addr::LOCALHOST));
+    /// } else {
+    ///     assert_eq!(beacon.received_on, None);
+    /// }
+    /// ```
+    pub received_on: Option<Ipv4Addr>,
+    /// The name of the sender's host, if it included it (see `BeaconSender::with_hostname()`)
+    pub hostname: Option<String>,
+    /// The port the service is running on
+    pub service_port: u16,
+    /// The name of the service sending the beacon
+    #[cfg_attr(feature = "serde", serde(with = "service_name"))]
+    pub service_name: Vec<u8>,
+    /// How long the sender says the information in this beacon is valid for, if it set a lease
+    pub lease: Option<Duration>,
+    /// The priority of this instance of the service, lowest first. `0` if the sender set none
+    pub priority: u16,
+    /// The relative weight of this instance among those with the same priority. `0` if the
+    /// sender set none
+    pub weight: u16,
+    /// The deployment generation of the sender, if it set one
+    pub generation: Option<u32>,
+    /// Whether the beacon was re-broadcast by a `DiscoveryRelay`, rather than received directly
+    pub relayed: bool,
+    /// The key/value metadata the sender attached, empty if it attached none
+    pub metadata: HashMap<String, String>,
+    /// The number of beacons the sender sent before this one, so a listener can notice gaps
+    /// from dropped beacons. `0` if the sender did not include one
+    pub sequence: u32,
+    /// When the sender says it sent the beacon, if it included the time (see
+    /// `BeaconSender::with_timestamps()`)
+    pub sent_at: Option<SystemTime>,
+    /// Whether the sender is shutting down and the service should be forgotten, see
+    /// `BeaconSender::send_goodbye()`
+    pub goodbye: bool,
+    /// The complete datagram the beacon was parsed from, if the listener was set to capture
+    /// them with `BeaconListener::capture_raw_payloads()`
+    pub raw_payload: Option<Vec<u8>>,
+}
+
+impl Beacon {
+    /// Parse the `payload` of a datagram received from `source_address` (e.g. using
+    /// `BeaconListener::recv_into()`) into a `Beacon`, returning `None` if it is not a valid beacon.
+    /// No service name filtering is applied.
+    ///
+    /// ```
+    /// use simpdiscoverylib::Beacon;
+    /// use std::net::SocketAddr;
+    ///
+    /// let source: SocketAddr = "192.168.1.20:40000".parse().expect("Invalid address");
+    /// let mut payload = vec![0xbe, 0xef, 0x01, 0x1f, 0x90, 0x00, 0x04];
+    /// payload.extend_from_slice(b"test");
+    /// payload.extend_from_slice(&[0xf8, 0x25]);
+    ///
+    /// let beacon = Beacon::parse_in_place(&payload, source).expect("Not a valid beacon");
+    /// assert_eq!(beacon.service_name, b"test");
+    /// assert_eq!(beacon.service_port, 8080);
+    /// assert_eq!(beacon.service_ip, "192.168.1.20");
+    /// assert_eq!(beacon.source_addr, source);
+    /// assert_eq!(beacon.protocol_version, 1);
+    /// assert_eq!(Beacon::parse_in_place(&payload, source), Some(beacon.clone()));
+    ///
+    /// assert!(Beacon::parse_in_place(b"not a beacon", source).is_none());
+    /// // A corrupt beacon, or a stray packet that happens to start with the magic number, fails
+    /// // the checksum
+    /// let mut corrupt = payload.clone();
+    /// corrupt[3] = 0x2f;
+    /// assert!(Beacon::parse_in_place(&corrupt, source).is_none());
+    /// // A beacon from a future version of the format is not misparsed
+    /// payload[2] = 2;
+    /// assert!(Beacon::parse_in_place(&payload, source).is_none());
+    /// ```
+    pub fn parse_in_place(payload: &[u8], source_address: SocketAddr) -> Option<Beacon> {
+        Self::parse_with_magic_number(payload, source_address, MAGIC_NUMBER)
+    }
+
+    /// Parse the `payload` of a datagram into a `Beacon` like `parse_in_place()`, for beacons
+    /// starting with `magic_number` rather than the default (see `BeaconSender::with_magic_number()`)
+    pub fn parse_with_magic_number(payload: &[u8], source_address: SocketAddr, magic_number: u16) -> Option<Beacon> {
+        match Self::parse_datagram(payload, source_address, magic_number)? {
+            (beacon, DatagramKind::Beacon) => Some(beacon),
+            _ => None,
+        }
+    }
+
+    /*
+        Parse a beacon, a query or an ack, returning the beacon (or the service named in a
+        query or ack) and which it is
+     */
+    pub(crate) fn parse_datagram(payload: &[u8], source_address: SocketAddr,
+                                 magic_number: u16) -> Option<(Beacon, DatagramKind)> {
+        if payload.len() < HEADER_SIZE + CHECKSUM_SIZE || array_of_u8_to_u16(&payload[0..2]) != magic_number {
+            return None;
+        }
+
+        let (payload, checksum) = payload.split_at(payload.len() - CHECKSUM_SIZE);
+        if array_of_u8_to_u16(checksum) != fletcher16(payload) {
+            trace!("Skipping beacon that fails its checksum");
+            return None;
+        }
+
+        let protocol_version = payload[2];
+        if protocol_version != PROTOCOL_VERSION {
+            trace!("Skipping beacon with unknown protocol version {}", protocol_version);
+            return None;
+        }
+
+        let service_port = array_of_u8_to_u16(&payload[3..5]);
+        let name_end = HEADER_SIZE + array_of_u8_to_u16(&payload[5..7]) as usize;
+        let service_name = payload.get(HEADER_SIZE..name_end)?.to_vec();
+
+        let mut beacon = Beacon {
+            service_ip: source_address.ip().to_string(),
+            source_addr: source_address,
+            service_port,
+            service_name,
+            protocol_version,
+            ..Default::default()
+        };
+
+        let mut kind = DatagramKind::Beacon;
+        Self::parse_fields(&payload[name_end..], &mut beacon, &mut kind, false)?;
+
+        Some((beacon, kind))
+    }
+
+    /*
+        Parse the optional fields of a beacon into `beacon`, returning `None` if they are malformed.
+        `inflated` fields were compressed, and may not contain another compressed field
+     */
+    #[cfg_attr(not(feature = "compression"), allow(unused_variables))]
+    fn parse_fields(mut fields: &[u8], beacon: &mut Beacon, kind: &mut DatagramKind, inflated: bool) -> Option<()> {
+        while !fields.is_empty() {
+            let header = fields.get(..FIELD_HEADER_SIZE)?;
+            let value_end = FIELD_HEADER_SIZE + array_of_u8_to_u16(&header[1..3]) as usize;
+            let value = fields.get(FIELD_HEADER_SIZE..value_end)?;
+            match (header[0], value.len()) {
+                (LEASE_FIELD, 4) => {
+                    let millis = u32::from_be_bytes([value[0], value[1], value[2], value[3]]);
+                    beacon.lease = Some(Duration::from_millis(millis as u64));
+                }
+                (PRIORITY_FIELD, 2) => beacon.priority = array_of_u8_to_u16(value),
+                (WEIGHT_FIELD, 2) => beacon.weight = array_of_u8_to_u16(value),
+                (GENERATION_FIELD, 4) =>
+                    beacon.generation = Some(u32::from_be_bytes([value[0], value[1], value[2], value[3]])),
+                (ADVERTISED_IP_FIELD, 4) =>
+                    beacon.service_ip = Ipv4Addr::new(value[0], value[1], value[2], value[3]).to_string(),
+                (ADVERTISED_IP_FIELD, 16) => {
+                    let mut octets = [0; 16];
+                    octets.copy_from_slice(value);
+                    beacon.service_ip = Ipv6Addr::from(octets).to_string();
+                }
+                (HOSTNAME_FIELD, _) => beacon.hostname = Some(String::from_utf8_lossy(value).into_owned()),
+                (RELAYED_FIELD, 0) => beacon.relayed = true,
+                (METADATA_FIELD, _) => beacon.metadata = parse_metadata(value)?,
+                (SEQUENCE_FIELD, 4) =>
+                    beacon.sequence = u32::from_be_bytes([value[0], value[1], value[2], value[3]]),
+                (SENT_AT_FIELD, 8) => {
+                    let mut millis = [0; 8];
+                    millis.copy_from_slice(value);
+                    beacon.sent_at = Some(UNIX_EPOCH + Duration::from_millis(u64::from_be_bytes(millis)));
+                }
+                (QUERY_FIELD, 0) => *kind = DatagramKind::Query,
+                (ACK_FIELD, 0) => *kind = DatagramKind::Ack,
+                (GOODBYE_FIELD, 0) => beacon.goodbye = true,
+                #[cfg(feature = "compression")]
+                (COMPRESSED_FIELD, _) if !inflated =>
+                    Self::parse_fields(&compression::inflate(value)?, beacon, kind, true)?,
+                #[cfg(feature = "signed-beacons")]
+                (SIGNATURE_FIELD, 32) => {} // verified by the listener, see `signing::verify()`
+                _ => trace!("Skipping unknown beacon field with tag {}", header[0]),
+            }
+            fields = &fields[value_end..];
+        }
+        Some(())
+    }
+
+    /// How long ago the sender says it sent the beacon, according to this host's clock, if it
+    /// included the time. `None` if it didn't, or if the time is in the future because the clocks
+    /// of the sender and this host are not in sync
+    pub fn age(&self) -> Option<Duration> {
+        SystemTime::now().duration_since(self.sent_at?).ok()
+    }
+
+    /// The service name as a string, with any invalid UTF-8 replaced by `U+FFFD`. Use
+    /// `service_name` directly for services with binary names
+    ///
+    /// ```
+    /// use simpdiscoverylib::Beacon;
+    ///
+    /// let beacon = Beacon { service_name: "_my_service._tcp.local".as_bytes().to_vec(), ..Default::default() };
+    /// assert_eq!(beacon.service_name_str(), "_my_service._tcp.local");
+    /// ```
+    pub fn service_name_str(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.service_name)
+    }
+
+    /// A hash of everything the sender put in the beacon except the fields that change with
+    /// every beacon sent (`sequence` and `sent_at`), so repeats of a beacon hash the same
+    /// while any change to it (e.g. to its metadata) hashes differently. Use it as the key of
+    /// `BeaconListener::with_debounce_by()` to drop only repeats that say nothing new
+    ///
+    /// ```
+    /// use simpdiscoverylib::Beacon;
+    /// use std::collections::HashMap;
+    ///
+    /// let beacon = Beacon { service_port: 8080, sequence: 1, ..Default::default() };
+    /// let repeat = Beacon { sequence: 2, ..beacon.clone() };
+    /// let changed = Beacon { metadata: HashMap::from([("version".into(), "2".into())]), ..repeat.clone() };
+    /// assert_eq!(beacon.content_hash(), repeat.content_hash());
+    /// assert_ne!(beacon.content_hash(), changed.content_hash());
+    /// ```
+    pub fn content_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.protocol_version.hash(&mut hasher);
+        self.service_ip.hash(&mut hasher);
+        self.hostname.hash(&mut hasher);
+        self.service_port.hash(&mut hasher);
+        self.service_name.hash(&mut hasher);
+        self.lease.hash(&mut hasher);
+        self.priority.hash(&mut hasher);
+        self.weight.hash(&mut hasher);
+        self.generation.hash(&mut hasher);
+        self.relayed.hash(&mut hasher);
+        // Sorted, as the order of a `HashMap` differs between maps with the same entries
+        let mut metadata: Vec<_> = self.metadata.iter().collect();
+        metadata.sort();
+        metadata.hash(&mut hasher);
+        self.goodbye.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /*
+        The key identifying the sender of `beacon` by its service IP, port and name
+     */
+    fn sender_hash(beacon: &Beacon) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        (&beacon.service_ip, beacon.service_port, &beacon.service_name).hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+/// Select one of `beacons` for a client to use, the way DNS SRV records are selected
+/// (see [RFC 2782](https://www.rfc-editor.org/rfc/rfc2782)): only instances with the lowest
+/// `priority` are considered, and one of those is picked at random with a probability
+/// proportional to its `weight`. Instances with a weight of `0` are only selected (uniformly)
+/// when every instance in their priority group has a weight of `0`.
+/// Returns `None` if `beacons` is empty
+///
+/// ```
+/// use simpdiscoverylib::{select_instance, Beacon};
+///
+/// let beacons = vec![
+///     Beacon { service_port: 1, priority: 10, weight: 1, ..Default::default() },
+///     Beacon { service_port: 2, priority: 10, weight: 3, ..Default::default() },
+///     Beacon { service_port: 3, priority: 20, weight: 100, ..Default::default() },
+/// ];
+///
+/// let mut counts = [0; 4];
+/// for _ in 0..4000 {
+///     let selected = select_instance(&beacons).expect("No instance selected");
+///     counts[selected.service_port as usize] += 1;
+/// }
+///
+/// // The higher priority number is never selected while lower ones are available
+/// assert_eq!(counts[3], 0);
+/// // The instance with weight 3 is selected roughly three times as often as the one with weight 1
+/// assert!(counts[1] > 800 && counts[1] < 1200, "weight 1 selected {} times", counts[1]);
+/// assert!(counts[2] > 2800 && counts[2] < 3200, "weight 3 selected {} times", counts[2]);
+/// ```
+pub fn select_instance(beacons: &[Beacon]) -> Option<&Beacon> {
+    let lowest_priority = beacons.iter().map(|beacon| beacon.priority).min()?;
+    let group: Vec<&Beacon> = beacons.iter()
+        .filter(|beacon| beacon.priority == lowest_priority)
+        .collect();
+
+    let total_weight: u64 = group.iter().map(|beacon| beacon.weight as u64).sum();
+    if total_weight == 0 {
+        return Some(group[(random_u64() % group.len() as u64) as usize]);
+    }
+
+    let selected = random_u64() % total_weight;
+    let mut running_weight = 0;
+    for beacon in &group {
+        running_weight += beacon.weight as u64;
+        if running_weight > selected {
+            return Some(beacon);
+        }
+    }
+
+    group.last().copied()
+}
+
+/*
+    A random number that is good enough for spreading load, without depending on a random crate.
+    Each `RandomState` is seeded with new random keys.
+ */
+fn random_u64() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos());
+    hasher.finish()
+}
+
+/*
+    `period` moved by an offset within `jitter` either way, picked using `random`
+ */
+fn jittered(period: Duration, jitter: Duration, random: u64) -> Duration {
+    let jitter_nanos = u64::try_from(jitter.as_nanos()).unwrap_or(u64::MAX).min(u64::MAX / 2);
+    let offset = Duration::from_nanos(random % (2 * jitter_nanos + 1));
+    period.saturating_add(offset).saturating_sub(Duration::from_nanos(jitter_nanos))
+}
+
+/*
+    The delay before retrying after a failure, doubling the `previous` delay if the last attempt
+    also failed, from `period` up to `max_backoff`
+ */
+fn backoff_after(previous: Option<Duration>, period: Duration, max_backoff: Duration) -> Duration {
+    previous.map_or(period, |previous| previous.saturating_mul(2)).min(max_backoff)
+}
+
+// `SocketAddr` has no default, so the source address defaults to "0.0.0.0:0"
+impl Default for Beacon {
+    fn default() -> Self {
+        Beacon {
+            protocol_version: PROTOCOL_VERSION,
+            service_ip: String::new(),
+            source_addr: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            received_on: None,
+            hostname: None,
+            service_port: 0,
+            service_name: vec![],
+            lease: None,
+            priority: 0,
+            weight: 0,
+            generation: None,
+            relayed: false,
+            metadata: HashMap::new(),
+            sequence: 0,
+            sent_at: None,
+            goodbye: false,
+            raw_payload: None,
+        }
+    }
+}
+
+/*
+    All the fields participate, as in the derived `PartialEq`. `HashMap` doesn't implement `Hash`,
+    so the metadata is hashed in sorted order to be independent of its iteration order
+ */
+impl Hash for Beacon {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let Beacon { protocol_version, service_ip, source_addr, received_on, hostname, service_port, service_name,
+            lease, priority, weight, generation, relayed, metadata, sequence, sent_at, goodbye,
+            raw_payload } = self;
+        protocol_version.hash(state);
+        service_ip.hash(state);
+        source_addr.hash(state);
+        received_on.hash(state);
+        hostname.hash(state);
+        service_port.hash(state);
+        service_name.hash(state);
+        lease.hash(state);
+        priority.hash(state);
+        weight.hash(state);
+        generation.hash(state);
+        relayed.hash(state);
+        let mut entries: Vec<(&String, &String)> = metadata.iter().collect();
+        entries.sort();
+        entries.hash(state);
+        sequence.hash(state);
+        sent_at.hash(state);
+        goodbye.hash(state);
+        raw_payload.hash(state);
+    }
+}
+
+impl std::fmt::Display for Beacon {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let service_name = String::from_utf8(self.service_name.clone()).unwrap_or_else(|_| "Invalid UTF-8 String".into());
+        write!(f, "ServiceName: '{}', Service IP: {}, Service Port: {}", service_name, self.service_ip, self.service_port)
+    }
+}
+
+/// `NoMatchReport` describes what a `BeaconListener` saw when `wait()` timed out without
+/// receiving a matching beacon. It is carried by the `DiscoveryError::Timeout` returned
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NoMatchReport {
+    /// The service name that was being waited for, followed by `*` if waiting for any service
+    /// name starting with it, or the service names separated by `|` if waiting for any of several.
+    /// `*` if waiting for any beacon
+    pub service_name: Vec<u8>,
+    /// The number of beacons received that did not match
+    pub non_matching: usize,
+    /// The number of different source IPs those beacons came from
+    pub sources: usize,
+}
+
+impl std::fmt::Display for NoMatchReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "saw {} beacons from {} sources, none matched '{}'", self.non_matching, self.sources,
+               String::from_utf8_lossy(&self.service_name))
+    }
+}
+
+impl std::error::Error for NoMatchReport {}
+
+/*
+    How a `BeaconListener` matches the service names of the beacons it receives
+ */
+enum ServiceNameFilter {
+    Exact(Vec<u8>),
+    Prefix(Vec<u8>),
+    AnyOf(BTreeSet<Vec<u8>>),
+    All,
+}
+
+impl ServiceNameFilter {
+    fn matches(&self, service_name: &[u8]) -> bool {
+        match self {
+            ServiceNameFilter::Exact(name) => service_name == name.as_slice(),
+            ServiceNameFilter::Prefix(prefix) => service_name.starts_with(prefix),
+            ServiceNameFilter::AnyOf(names) => names.contains(service_name),
+            ServiceNameFilter::All => true,
+        }
+    }
+}
+
+impl std::fmt::Display for ServiceNameFilter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServiceNameFilter::Exact(name) => write!(f, "{}", String::from_utf8_lossy(name)),
+            ServiceNameFilter::Prefix(prefix) => write!(f, "{}*", String::from_utf8_lossy(prefix)),
+            ServiceNameFilter::AnyOf(names) => {
+                let names: Vec<String> = names.iter().map(|name| String::from_utf8_lossy(name).into_owned()).collect();
+                write!(f, "{}", names.join("|"))
+            }
+            ServiceNameFilter::All => write!(f, "*"),
+        }
+    }
+}
+
+/// `BeaconListener` listens for new `Beacons` on the specified port
+///
+/// # Example of using `BeaconListener` with timeout
+/// ```
+/// use simpdiscoverylib::BeaconListener;
+/// use std::time::Duration;
+/// use portpicker::pick_unused_port;
+///
+/// let listening_port = pick_unused_port().expect("Could not get a free port to listen on");
+/// let listener = BeaconListener::new("_my_service._tcp.local".as_bytes(), listening_port)
+///     .expect("Could not create listener");
+///
+/// // Avoid blocking tests by setting a short timeout, expect an error, as there is no sender setup
+/// assert!(listener.wait(Some(Duration::from_millis(1))).is_err());
+/// ```
+pub struct BeaconListener {
+    socket: UdpSocket,
+    filter: ServiceNameFilter,
+    multicast_group: Option<MulticastGroup>,
+    rate_limiter: Option<Mutex<RateLimiter>>,
+    debouncer: Option<Mutex<Debouncer>>,
+    max_dropped: Option<usize>,
+    max_beacon_size: usize,
+    #[cfg(feature = "signed-beacons")]
+    verification_key: Option<Vec<u8>>,
+    #[cfg(all(feature = "packet-info", target_os = "linux"))]
+    packet_info: bool,
+    magic_number: u16,
+    coalesce_bursts: bool,
+    capture_raw_payloads: bool,
+    allowed_ports: Option<RangeInclusive<u16>>,
+    schema: Option<MetadataSchema>,
+    label: Option<String>,
+    sink: Option<Box<dyn BeaconSink>>,
+    counters: ListenerCounters,
+}
+
+/*
+    The multicast group a `BeaconListener` has joined, to leave when it is dropped
+ */
+#[derive(Clone, Copy)]
+enum MulticastGroup {
+    V4(Ipv4Addr),
+    V6(Ipv6Addr, u32),
+}
+
+/// `ListenerStats` is a snapshot of the counters of a `BeaconListener`, see
+/// `BeaconListener::take_stats()`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ListenerStats {
+    /// The number of datagrams received
+    pub received: u64,
+    /// The number of beacons received that matched the service name and were returned
+    pub matched: u64,
+    /// The number of beacons received that did not match the service name
+    pub non_matching: u64,
+    /// The number of datagrams dropped as they were not valid beacons
+    pub dropped_invalid: u64,
+    /// The number of beacons dropped by the per source rate limit
+    pub dropped_rate_limited: u64,
+    /// The number of beacons dropped for advertising a service port outside the allowed range
+    pub dropped_port: u64,
+    /// The number of datagrams dropped as they were larger than the largest beacon the listener
+    /// receives (see `BeaconListener::with_buffer_size()`), and so were truncated when received
+    pub dropped_truncated: u64,
+    /// The number of beacons dropped as they were not signed with the listener's verification key
+    /// (see the `signed-beacons` feature)
+    pub dropped_unverified: u64,
+    /// The number of beacons dropped as their metadata didn't conform to the listener's schema
+    /// (see `BeaconListener::require_schema()`)
+    pub dropped_schema: u64,
+}
+
+#[derive(Default)]
+struct ListenerCounters {
+    received: AtomicU64,
+    matched: AtomicU64,
+    non_matching: AtomicU64,
+    dropped_invalid: AtomicU64,
+    dropped_rate_limited: AtomicU64,
+    dropped_port: AtomicU64,
+    dropped_truncated: AtomicU64,
+    dropped_unverified: AtomicU64,
+    dropped_schema: AtomicU64,
+}
+
+impl ListenerCounters {
+    /*
+        Read each counter in turn with `read`. They are independent atomics, updated without a
+        lock as beacons are received, so the values read are only consistent with each other
+        when no beacon is being received meanwhile
+     */
+    fn snapshot<F: Fn(&AtomicU64) -> u64>(&self, read: F) -> ListenerStats {
+        ListenerStats {
+            received: read(&self.received),
+            matched: read(&self.matched),
+            non_matching: read(&self.non_matching),
+            dropped_invalid: read(&self.dropped_invalid),
+            dropped_rate_limited: read(&self.dropped_rate_limited),
+            dropped_port: read(&self.dropped_port),
+            dropped_truncated: read(&self.dropped_truncated),
+            dropped_unverified: read(&self.dropped_unverified),
+            dropped_schema: read(&self.dropped_schema),
+        }
+    }
+}
+
+fn increment(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/*
+    Call `receive` again whenever it is interrupted by a signal before receiving anything
+    (`EINTR`), as that is not a failure of the socket
+ */
+fn retry_interrupted<T, F: FnMut() -> io::Result<T>>(mut receive: F) -> io::Result<T> {
+    loop {
+        match receive() {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => trace!("Receive interrupted: retrying"),
+            result => return result,
+        }
+    }
+}
+
+/*
+    Counts beacons received from each source IP within the current one second window
+ */
+struct RateLimiter {
+    max_per_sec: u32,
+    windows: HashMap<IpAddr, (Instant, u32)>,
+}
+
+impl RateLimiter {
+    fn allow(&mut self, source: IpAddr) -> bool {
+        let now = Instant::now();
+        let one_second = Duration::from_secs(1);
+        if self.windows.len() > MAX_RATE_LIMITED_SOURCES {
+            self.windows.retain(|_, (start, _)| now.duration_since(*start) < one_second);
+        }
+
+        let (start, count) = self.windows.entry(source).or_insert((now, 0));
+        if now.duration_since(*start) >= one_second {
+            *start = now;
+            *count = 0;
+        }
+        *count += 1;
+        *count <= self.max_per_sec
+    }
+}
+
+/*
+    When the last beacon with each key, by default identifying its sender by service IP, port
+    and name, was let through, so repeats within the debounce window can be dropped
+ */
+struct Debouncer {
+    window: Duration,
+    key: Box<dyn Fn(&Beacon) -> u64 + Send + Sync>,
+    last_returned: HashMap<u64, Instant>,
+}
+
+impl Debouncer {
+    fn allow(&mut self, beacon: &Beacon) -> bool {
+        let now = Instant::now();
+        let window = self.window;
+        if self.last_returned.len() > MAX_DEBOUNCED_SENDERS {
+            self.last_returned.retain(|_, returned| now.duration_since(*returned) < window);
+        }
+
+        let key = (self.key)(beacon);
+        match self.last_returned.get(&key) {
+            Some(returned) if now.duration_since(*returned) < window => false,
+            _ => {
+                self.last_returned.insert(key, now);
+                true
+            }
+        }
+    }
+}
+
+impl BeaconListener {
+    /// Create a new `BeaconListener` on `port` with an option `filter` to be applied to incoming
+    /// beacons. This binds to address "0.0.0.0:listening_port".
+    ///
+    /// Listeners only receive, which doesn't need the broadcast permission (`SO_BROADCAST`), so
+    /// they don't ask for it and can be created on hosts that deny it. See `with_broadcast()`
+    pub fn new(service_name: &[u8], listening_port: u16) -> Result<Self> {
+        let listening_address = format!("{}:{}", LISTENING_ADDRESS, listening_port);
+        Self::bind(&listening_address, ServiceNameFilter::Exact(service_name.to_vec()))
+    }
+
+    /// Create a new `BeaconListener` like `new()`, with address reuse (`SO_REUSEADDR`, and
+    /// `SO_REUSEPORT` where supported) so that several listeners, in the same or independent
+    /// processes, can bind to the same port. Each shared listener receives every broadcast beacon.
+    /// A listener created with `new()` on the port still stops shared ones binding to it, and
+    /// vice versa
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let first = BeaconListener::new_shared("shared".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// let second = BeaconListener::new_shared("shared".as_bytes(), broadcast_port)
+    ///     .expect("Could not create second listener on the same port");
+    ///
+    /// BeaconSender::new(8080, "shared".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender")
+    ///     .send_one_beacon().expect("Could not send beacon");
+    ///
+    /// for listener in [first, second] {
+    ///     let beacon = listener.wait(Some(Duration::from_secs(5))).expect("Failed to receive beacon");
+    ///     assert_eq!(beacon.service_port, 8080);
+    /// }
+    /// ```
+    pub fn new_shared(service_name: &[u8], listening_port: u16) -> Result<Self> {
+        let listening_address = SocketAddr::from((Ipv4Addr::UNSPECIFIED, listening_port));
+        let socket = reusable_socket(listening_address)
+            .map_err(|source| DiscoveryError::Bind { address: listening_address.to_string(), source })?;
+        trace!("Socket bound with address reuse to: {}", listening_address);
+        Self::from_socket(socket, ServiceNameFilter::Exact(service_name.to_vec()))
+    }
+
+    /// Create a new `BeaconListener` like `new()`, that matches beacons with any service name
+    /// starting with `prefix` rather than exactly one name, e.g. to discover all the instances of
+    /// services named `_myapp._tcp.local.instance-{n}`. The `Beacon`s returned have the full name
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let listener = BeaconListener::new_with_prefix("_myapp._tcp.local.".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// let beacon = BeaconSender::new(8080, "_myapp._tcp.local.instance-1".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// beacon.send_one_beacon().expect("Could not send beacon");
+    ///
+    /// let beacon = listener.wait(Some(Duration::from_secs(5))).expect("Failed to receive beacon");
+    /// assert_eq!(beacon.service_name, b"_myapp._tcp.local.instance-1");
+    /// ```
+    pub fn new_with_prefix(prefix: &[u8], listening_port: u16) -> Result<Self> {
+        let listening_address = format!("{}:{}", LISTENING_ADDRESS, listening_port);
+        Self::bind(&listening_address, ServiceNameFilter::Prefix(prefix.to_vec()))
+    }
+
+    /// Create a new `BeaconListener` like `new()`, that matches beacons with any of the service
+    /// names in `names`, e.g. to discover all the sub-services of an application with one socket
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let listener = BeaconListener::new_multi(vec![b"database".to_vec(), b"cache".to_vec()], broadcast_port)
+    ///     .expect("Could not create listener");
+    /// for (service_port, service_name) in [(5432, "database"), (6379, "cache")] {
+    ///     BeaconSender::new(service_port, service_name.as_bytes(), broadcast_port)
+    ///         .expect("Could not create sender")
+    ///         .send_one_beacon().expect("Could not send beacon");
+    /// }
+    ///
+    /// let beacons = listener.wait_many(Duration::from_millis(300), Some(2)).expect("Failed to receive beacons");
+    /// assert_eq!(beacons.len(), 2);
+    /// ```
+    pub fn new_multi(names: Vec<Vec<u8>>, listening_port: u16) -> Result<Self> {
+        let listening_address = format!("{}:{}", LISTENING_ADDRESS, listening_port);
+        Self::bind(&listening_address, ServiceNameFilter::AnyOf(names.into_iter().collect()))
+    }
+
+    /// Create a new `BeaconListener` like `new()`, that matches every valid beacon whatever its
+    /// service name, e.g. to take an inventory of all the services on the network using `wait_many()`
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let listener = BeaconListener::new_accept_all(broadcast_port).expect("Could not create listener");
+    /// for (service_port, service_name) in [(80, "web"), (22, "ssh")] {
+    ///     BeaconSender::new(service_port, service_name.as_bytes(), broadcast_port)
+    ///         .expect("Could not create sender")
+    ///         .send_one_beacon().expect("Could not send beacon");
+    /// }
+    ///
+    /// let beacons = listener.wait_many(Duration::from_millis(300), None).expect("Failed to receive beacons");
+    /// assert_eq!(beacons.len(), 2);
+    /// ```
+    pub fn new_accept_all(listening_port: u16) -> Result<Self> {
+        let listening_address = format!("{}:{}", LISTENING_ADDRESS, listening_port);
+        Self::bind(&listening_address, ServiceNameFilter::All)
+    }
+
+    /// Create a new `BeaconListener` like `new()`, but bound to the local address `iface_addr`
+    /// rather than "0.0.0.0", to control which interface of a multi-homed host beacons are
+    /// received on.
+    ///
+    /// On Linux a socket bound to a unicast address only receives datagrams sent to exactly that
+    /// address (e.g. with `BeaconSender::announce_to()`), and not broadcasts. To receive only the
+    /// broadcasts on one network, pass its broadcast address (see `broadcast_candidates()`)
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::net::Ipv4Addr;
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let port = pick_unused_port().expect("Could not get a free port");
+    /// let listener = BeaconListener::new_on_interface("loopback".as_bytes(), port, Ipv4Addr::LOCALHOST)
+    ///     .expect("Could not create listener");
+    /// let beacon = BeaconSender::new(8080, "loopback".as_bytes(), port)
+    ///     .expect("Could not create sender");
+    /// beacon.announce_to(format!("127.0.0.1:{port}").parse().expect("Invalid address"))
+    ///     .expect("Could not send beacon");
+    ///
+    /// let beacon = listener.wait(Some(Duration::from_secs(5))).expect("Failed to receive beacon");
+    /// assert_eq!(beacon.service_port, 8080);
+    /// ```
+    pub fn new_on_interface(service_name: &[u8], port: u16, iface_addr: Ipv4Addr) -> Result<Self> {
+        let listening_address = format!("{}:{}", iface_addr, port);
+        Self::bind(&listening_address, ServiceNameFilter::Exact(service_name.to_vec()))
+    }
+
+    /// Create a new `BeaconListener` bound to `127.0.0.1` on `port`, that receives beacons from a
+    /// `BeaconSender` created with `BeaconSender::new_loopback()` (or sent to it with
+    /// `BeaconSender::announce_to()`) but not broadcasts, e.g. for deterministic tests
+    pub fn new_loopback(service_name: &[u8], port: u16) -> Result<Self> {
+        let listening_address = format!("{}:{}", Ipv4Addr::LOCALHOST, port);
+        Self::bind(&listening_address, ServiceNameFilter::Exact(service_name.to_vec()))
+    }
+
+    /// Create a new `BeaconListener` on `port` that joins the IPv4 multicast group
+    /// `multicast_address`, to receive beacons from a `BeaconSender` created with
+    /// `BeaconSender::new_multicast()` for the same group and port. The group is joined
+    /// on the interface chosen by the OS, and left again when the listener is dropped
+    pub fn new_multicast(service_name: &[u8], multicast_address: Ipv4Addr,
+                         port: u16) -> Result<Self> {
+        let listening_address = format!("{}:{}", LISTENING_ADDRESS, port);
+        let mut listener = Self::bind(&listening_address, ServiceNameFilter::Exact(service_name.to_vec()))?;
+        listener.socket.join_multicast_v4(&multicast_address, &Ipv4Addr::UNSPECIFIED)
+            .map_err(|e|
+                io::Error::new(e.kind(),
+                               format!("SimpDiscover::BeaconListener could not join multicast group {multicast_address} ({e})")))?;
+        trace!("Joined multicast group: {}", multicast_address);
+        listener.multicast_group = Some(MulticastGroup::V4(multicast_address));
+        Ok(listener)
+    }
+
+    /// Create a new `BeaconListener` on `port` that joins the IPv6 multicast group
+    /// `multicast_address` on the interface with index `scope_id` (or one chosen by the OS if `0`),
+    /// to receive beacons from a `BeaconSender` created with `BeaconSender::new_v6()` for the
+    /// same group and port. The group is left again when the listener is dropped
+    pub fn new_v6(service_name: &[u8], multicast_address: Ipv6Addr, port: u16, scope_id: u32) -> Result<Self> {
+        if !multicast_address.is_multicast() {
+            return Err(DiscoveryError::InvalidAddress(format!("{multicast_address} is not a multicast address")));
+        }
+        let listening_address = format!("[{}]:{}", Ipv6Addr::UNSPECIFIED, port);
+        let mut listener = Self::bind(&listening_address, ServiceNameFilter::Exact(service_name.to_vec()))?;
+        listener.socket.join_multicast_v6(&multicast_address, scope_id)
+            .map_err(|e|
+                io::Error::new(e.kind(),
+                               format!("SimpDiscover::BeaconListener could not join multicast group {multicast_address} ({e})")))?;
+        trace!("Joined multicast group: {}", multicast_address);
+        listener.multicast_group = Some(MulticastGroup::V6(multicast_address, scope_id));
+        Ok(listener)
+    }
+
+    fn bind(listening_address: &str, filter: ServiceNameFilter) -> Result<Self> {
+        let socket = UdpSocket::bind(listening_address)
+            .map_err(|source| DiscoveryError::Bind { address: listening_address.to_string(), source })?;
+        trace!("Socket bound to: {}", listening_address);
+        Self::from_socket(socket, filter)
+    }
+
+    fn from_socket(socket: UdpSocket, filter: ServiceNameFilter) -> Result<Self> {
+        #[cfg(all(feature = "packet-info", target_os = "linux"))]
+        let packet_info = socket.local_addr()?.is_ipv4();
+        #[cfg(all(feature = "packet-info", target_os = "linux"))]
+        if packet_info {
+            packet_info::enable(&socket)?;
+        }
+
+        Ok(Self {
+            socket,
+            filter,
+            multicast_group: None,
+            rate_limiter: None,
+            debouncer: None,
+            max_dropped: None,
+            max_beacon_size: MAX_INCOMING_BEACON_SIZE,
+            #[cfg(feature = "signed-beacons")]
+            verification_key: None,
+            #[cfg(all(feature = "packet-info", target_os = "linux"))]
+            packet_info,
+            magic_number: MAGIC_NUMBER,
+            coalesce_bursts: false,
+            capture_raw_payloads: false,
+            allowed_ports: None,
+            schema: None,
+            label: None,
+            sink: None,
+            counters: ListenerCounters::default(),
+        })
+    }
+
+    /// Enable sending broadcasts from the listener's socket (`SO_BROADCAST`), which receiving
+    /// beacons doesn't need, for platforms or uses of the socket that do. Fails with
+    /// `DiscoveryError::Broadcast` if the OS refuses
+    ///
+    /// ```
+    /// use simpdiscoverylib::BeaconListener;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let port = pick_unused_port().expect("Could not get a free port");
+    /// let listener = BeaconListener::new("broadcasting".as_bytes(), port)
+    ///     .expect("Could not create listener")
+    ///     .with_broadcast()
+    ///     .expect("Could not enable broadcast");
+    /// ```
+    pub fn with_broadcast(self) -> Result<Self> {
+        self.socket.set_broadcast(true).map_err(DiscoveryError::Broadcast)?;
+        info!("{}Broadcast mode set to ON", self.log_prefix());
+        Ok(self)
+    }
+
+    /// The service name this listener matches, or `None` if it was created to match service names
+    /// by prefix, several service names or every service (see `filter_description()`)
+    ///
+    /// ```
+    /// use simpdiscoverylib::BeaconListener;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let port = pick_unused_port().expect("Could not get a free port");
+    /// let listener = BeaconListener::new("inspected".as_bytes(), port).expect("Could not create listener");
+    /// assert_eq!(listener.service_name(), Some("inspected".as_bytes()));
+    /// assert_eq!(listener.local_addr().expect("No local address").port(), port);
+    ///
+    /// let port = pick_unused_port().expect("Could not get a free port");
+    /// let listener = BeaconListener::new_with_prefix("printer-".as_bytes(), port)
+    ///     .expect("Could not create listener");
+    /// assert_eq!(listener.service_name(), None);
+    /// assert_eq!(listener.filter_description(), "printer-*");
+    /// ```
+    pub fn service_name(&self) -> Option<&[u8]> {
+        match &self.filter {
+            ServiceNameFilter::Exact(name) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// A description of the service names this listener matches, as used in its log lines and
+    /// `NoMatchReport`: the service name, a prefix followed by `*`, several service names
+    /// separated by `|`, or `*` for every service
+    pub fn filter_description(&self) -> String {
+        self.filter.to_string()
+    }
+
+    /// Change the listener to match beacons for `service_name` only, replacing whatever it
+    /// matched before, e.g. to discover several services one after the other on the same socket
+    /// without rebinding it. Beacons already queued on the socket are matched against the new
+    /// service name when they are received
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let mut listener = BeaconListener::new("first-phase".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// let first = BeaconSender::new(8080, "first-phase".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// first.send_one_beacon().expect("Could not send beacon");
+    /// assert_eq!(listener.wait(Some(Duration::from_secs(5))).expect("Failed to receive beacon").service_port, 8080);
+    ///
+    /// listener.set_filter("second-phase".as_bytes());
+    /// assert_eq!(listener.service_name(), Some("second-phase".as_bytes()));
+    /// let second = BeaconSender::new(8081, "second-phase".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// first.send_one_beacon().expect("Could not send beacon");
+    /// second.send_one_beacon().expect("Could not send beacon");
+    /// assert_eq!(listener.wait(Some(Duration::from_secs(5))).expect("Failed to receive beacon").service_port, 8081);
+    /// ```
+    pub fn set_filter(&mut self, service_name: &[u8]) {
+        self.filter = ServiceNameFilter::Exact(service_name.to_vec());
+        info!("{}Filter set to '{}'", self.log_prefix(), self.filter);
+    }
+
+    /// Set the size of the socket's receive buffer (`SO_RCVBUF`), where beacons queue until they
+    /// are received. When many hosts answer at once, e.g. during a scan of a busy network, the OS
+    /// default (often around 200 KiB) can overflow and beacons are silently dropped. Each queued
+    /// beacon takes about 1 KiB of buffer, so 1 MiB to 4 MiB suits LANs with hundreds of
+    /// services. The OS may round the size, and caps it (on Linux at `net.core.rmem_max`)
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let small = BeaconListener::new_shared("flooded".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// small.set_recv_buffer_size(4096).expect("Could not set receive buffer size");
+    /// let large = BeaconListener::new_shared("flooded".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// large.set_recv_buffer_size(1 << 20).expect("Could not set receive buffer size");
+    ///
+    /// let beacon = BeaconSender::new(8080, "flooded".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// for _ in 0..200 {
+    ///     beacon.send_one_beacon().expect("Could not send beacon");
+    /// }
+    /// std::thread::sleep(std::time::Duration::from_millis(100));
+    ///
+    /// let count = |listener: &BeaconListener| std::iter::from_fn(|| listener.try_receive()
+    ///     .expect("Could not poll")).count();
+    /// assert!(count(&small) < count(&large));
+    /// ```
+    pub fn set_recv_buffer_size(&self, bytes: usize) -> Result<()> {
+        socket2::SockRef::from(&self.socket).set_recv_buffer_size(bytes)?;
+        info!("{}Receive buffer size set to: {}", self.log_prefix(), bytes);
+        Ok(())
+    }
+
+    /// The socket the listener receives beacons on, e.g. to set options the crate doesn't expose
+    /// such as the interface it is bound to. Misconfiguring the socket can break discovery: the
+    /// listener expects it to stay bound and blocking, and sets its read timeout on every wait
+    ///
+    /// ```
+    /// use simpdiscoverylib::BeaconListener;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let port = pick_unused_port().expect("Could not get a free port");
+    /// let listener = BeaconListener::new("tuned".as_bytes(), port).expect("Could not create listener");
+    /// listener.socket().set_ttl(4).expect("Could not set TTL");
+    /// assert_eq!(listener.socket().local_addr().expect("No local address").port(), port);
+    /// ```
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// The local address and port the listener's socket is bound to, e.g. to report the port
+    /// when it was picked dynamically
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Get a snapshot of the listener's counters and reset them all to zero, e.g. for periodic
+    /// metrics export. Each counter is read and zeroed in a single atomic operation, so every
+    /// beacon is counted in exactly one snapshot, even while another thread is receiving.
+    /// The counters are not read all at once though, so while another thread is receiving a
+    /// snapshot can split a beacon across two intervals (e.g. `matched` counting one more beacon
+    /// than `received`): only the totals of each counter over successive snapshots are exact
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::net::UdpSocket;
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let listener = BeaconListener::new("counted".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// for service_name in ["other", "counted"] {
+    ///     BeaconSender::new(8080, service_name.as_bytes(), broadcast_port)
+    ///         .expect("Could not create sender")
+    ///         .send_one_beacon().expect("Could not send beacon");
+    /// }
+    /// listener.wait(Some(Duration::from_secs(5))).expect("Failed to receive beacon");
+    ///
+    /// let stats = listener.take_stats();
+    /// assert_eq!(stats.received, 2);
+    /// assert_eq!(stats.matched, 1);
+    /// assert_eq!(stats.non_matching, 1);
+    /// assert_eq!(listener.take_stats().received, 0);
+    ///
+    /// // A datagram too large to be a beacon is truncated on reception and dropped
+    /// let oversized = UdpSocket::bind("0.0.0.0:0").expect("Could not bind socket");
+    /// let mut datagram = vec![0xbe, 0xef, 0x01, 0x1f, 0x90, 0x00, 0x04];
+    /// datagram.extend_from_slice(b"counted");
+    /// datagram.resize(1500, 0);
+    /// oversized.send_to(&datagram, format!("127.0.0.1:{broadcast_port}")).expect("Could not send");
+    /// assert!(listener.wait(Some(Duration::from_millis(200))).is_err());
+    /// assert_eq!(listener.take_stats().dropped_truncated, 1);
+    /// ```
+    pub fn take_stats(&self) -> ListenerStats {
+        self.counters.snapshot(|counter| counter.swap(0, Ordering::Relaxed))
+    }
+
+    /// Get a snapshot of the listener's counters like `take_stats()`, but without resetting them,
+    /// e.g. to scrape them from another thread while it is waiting for beacons. As with
+    /// `take_stats()`, counters read while a beacon is being received may not agree with each other
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let listener = BeaconListener::new("scraped".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// BeaconSender::new(8080, "scraped".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender")
+    ///     .send_one_beacon().expect("Could not send beacon");
+    /// listener.wait(Some(Duration::from_secs(5))).expect("Failed to receive beacon");
+    ///
+    /// assert_eq!(listener.stats().matched, 1);
+    /// assert_eq!(listener.stats().matched, 1);
+    /// ```
+    pub fn stats(&self) -> ListenerStats {
+        self.counters.snapshot(|counter| counter.load(Ordering::Relaxed))
+    }
+
+    /// Include `label` in this listener's log lines, e.g. `[printer-finder] Waiting for beacon ...`,
+    /// to tell apart the logs of several listeners in one process
+    pub fn with_label(mut self, label: &str) -> Self {
+        self.label = Some(label.to_string());
+        self
+    }
+
+    /// Only accept beacons starting with `magic_number` instead of the default `0xbeef`, as sent by a
+    /// `BeaconSender` set to it with `BeaconSender::with_magic_number()`. Other datagrams are
+    /// dropped (and counted as invalid) as soon as they are read, before being parsed.
+    /// Answering queries with `respond_to_queries()` also expects them to start with `magic_number`
+    pub fn with_magic_number(mut self, magic_number: u16) -> Self {
+        self.magic_number = magic_number;
+        self
+    }
+
+    fn log_prefix(&self) -> String {
+        log_prefix(&self.label)
+    }
+
+    /// Only accept beacons advertising a service port within `ports`, e.g. `1024..=65535`.
+    /// Out of range ports indicate corruption or misconfiguration, so those beacons are dropped
+    /// (and counted, see `port_rejected_count()`)
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let listener = BeaconListener::new("ranged".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener")
+    ///     .allowed_port_range(1024..=65535);
+    ///
+    /// for service_port in [80, 8080] {
+    ///     BeaconSender::new(service_port, "ranged".as_bytes(), broadcast_port)
+    ///         .expect("Could not create sender")
+    ///         .send_one_beacon().expect("Could not send beacon");
+    /// }
+    ///
+    /// let beacon = listener.wait(Some(Duration::from_secs(5))).expect("Failed to receive beacon");
+    /// assert_eq!(beacon.service_port, 8080);
+    /// assert_eq!(listener.port_rejected_count(), 1);
+    /// ```
+    pub fn allowed_port_range(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.allowed_ports = Some(ports);
+        self
+    }
+
+    /// The number of beacons dropped because their service port was outside the range set with
+    /// `allowed_port_range()`, since the listener was created or `take_stats()` was last called
+    pub fn port_rejected_count(&self) -> u64 {
+        self.counters.dropped_port.load(Ordering::Relaxed)
+    }
+
+    /// When a matching beacon is received, also drain any further beacons from the same sender
+    /// that are immediately available (such as the rest of a startup burst) and return only the
+    /// most recent one. Beacons from other senders are left to be received as usual. The beacons
+    /// drained are checked, filtered, counted and recorded in the sink like any other, so one
+    /// dropped by e.g. the rate limit or debouncing is not returned in place of the last one matched
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let listener = BeaconListener::new("bursty".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener")
+    ///     .coalesce_bursts();
+    ///
+    /// let mut beacon = BeaconSender::new(8080, "bursty".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// for generation in 1..=5 {
+    ///     beacon = beacon.with_generation(generation);
+    ///     beacon.send_one_beacon().expect("Could not send beacon");
+    /// }
+    /// std::thread::sleep(Duration::from_millis(100));
+    ///
+    /// let received = listener.wait(Some(Duration::from_secs(5))).expect("Failed to receive beacon");
+    /// assert_eq!(received.generation, Some(5));
+    /// assert!(listener.wait(Some(Duration::from_millis(100))).is_err());
+    /// assert_eq!(listener.stats().received, 5);
+    /// assert_eq!(listener.stats().matched, 5);
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let limited = BeaconListener::new("bursty".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener")
+    ///     .coalesce_bursts()
+    ///     .per_source_rate_limit(2);
+    /// let mut beacon = BeaconSender::new(8080, "bursty".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// for generation in 1..=5 {
+    ///     beacon = beacon.with_generation(generation);
+    ///     beacon.send_one_beacon().expect("Could not send beacon");
+    /// }
+    /// std::thread::sleep(Duration::from_millis(100));
+    ///
+    /// let received = limited.wait(Some(Duration::from_secs(5))).expect("Failed to receive beacon");
+    /// assert_eq!(received.generation, Some(2));
+    /// assert_eq!(limited.rate_limited_count(), 3);
+    /// ```
+    pub fn coalesce_bursts(mut self) -> Self {
+        self.coalesce_bursts = true;
+        self
+    }
+
+    /// Keep the complete datagram each beacon was parsed from in `Beacon::raw_payload`, e.g. to
+    /// hexdump exactly what arrived when diagnosing discovery problems. Off by default, as it
+    /// costs an allocation per beacon
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let listener = BeaconListener::new("captured".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener")
+    ///     .capture_raw_payloads();
+    ///
+    /// let beacon = BeaconSender::new(8080, "captured".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// let sent = beacon.wire_bytes();
+    /// beacon.send_one_beacon().expect("Could not send beacon");
+    ///
+    /// let received = listener.wait(Some(Duration::from_secs(5))).expect("Failed to receive beacon");
+    /// assert_eq!(received.raw_payload, Some(sent));
+    /// ```
+    pub fn capture_raw_payloads(mut self) -> Self {
+        self.capture_raw_payloads = true;
+        self
+    }
+
+    /// Set the size of the largest beacon the listener receives, to `size` bytes rather than the
+    /// default of 1024, e.g. to receive larger beacons from other implementations of the beacon
+    /// format, or to save memory on constrained devices. Larger datagrams are truncated when received
+    /// and dropped (and counted, see `take_stats()`).
+    ///
+    /// `BeaconSender`s only send beacons of up to 1024 bytes, so a smaller size may drop some of them
+    ///
+    /// ```
+    /// use simpdiscoverylib::BeaconListener;
+    /// use std::net::UdpSocket;
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let port = pick_unused_port().expect("Could not get a free port");
+    /// let listener = BeaconListener::new("large".as_bytes(), port)
+    ///     .expect("Could not create listener")
+    ///     .with_buffer_size(4096);
+    ///
+    /// // A beacon with a 2000 byte field of an unknown type, which listeners skip
+    /// let mut payload = vec![0xbe, 0xef, 0x01, 0x1f, 0x90, 0x00, 0x05];
+    /// payload.extend_from_slice(b"large");
+    /// payload.extend_from_slice(&[0xf0, 0x07, 0xd0]);
+    /// payload.resize(payload.len() + 2000, 0);
+    /// let (mut sum1, mut sum2) = (0u16, 0u16);
+    /// for byte in &payload {
+    ///     sum1 = (sum1 + *byte as u16) % 255;
+    ///     sum2 = (sum2 + sum1) % 255;
+    /// }
+    /// payload.extend_from_slice(&[sum2 as u8, sum1 as u8]);
+    ///
+    /// let sender = UdpSocket::bind("0.0.0.0:0").expect("Could not bind socket");
+    /// sender.send_to(&payload, format!("127.0.0.1:{port}")).expect("Could not send");
+    /// let beacon = listener.wait(Some(Duration::from_secs(5))).expect("Failed to receive beacon");
+    /// assert_eq!(beacon.service_port, 8080);
+    /// ```
+    pub fn with_buffer_size(mut self, size: usize) -> Self {
+        self.max_beacon_size = size;
+        self
+    }
+
+    /*
+        A buffer one byte larger than the largest beacon, to detect larger datagrams being truncated
+     */
+    pub(crate) fn receive_buffer(&self) -> Vec<u8> {
+        vec![0; self.max_beacon_size + 1]
+    }
+
+    /// Stop waiting and return a `DiscoveryError::MalformedBeacon` if `max` datagrams in a row are
+    /// dropped (as invalid beacons, or by the rate limit or allowed port range) while waiting for
+    /// a beacon, rather than discarding junk traffic on a noisy network forever. Unlimited by default
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconListener, DiscoveryError};
+    /// use std::net::UdpSocket;
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let port = pick_unused_port().expect("Could not get a free port");
+    /// let listener = BeaconListener::new("noisy".as_bytes(), port)
+    ///     .expect("Could not create listener")
+    ///     .max_dropped_datagrams(3);
+    ///
+    /// let junk = UdpSocket::bind("0.0.0.0:0").expect("Could not bind socket");
+    /// for _ in 0..3 {
+    ///     junk.send_to(b"not a beacon", format!("127.0.0.1:{port}")).expect("Could not send");
+    /// }
+    ///
+    /// match listener.wait(Some(Duration::from_secs(5))) {
+    ///     Err(DiscoveryError::MalformedBeacon(_)) => {},
+    ///     _ => panic!("Expected a MalformedBeacon error"),
+    /// }
+    /// ```
+    pub fn max_dropped_datagrams(mut self, max: usize) -> Self {
+        self.max_dropped = Some(max);
+        self
+    }
+
+    /// Limit the number of beacons accepted from any one source IP to `max_per_sec` per second.
+    /// Beacons from a source over the limit are dropped (and counted, see `rate_limited_count()`)
+    /// while beacons from other sources continue to be processed, so a single noisy host cannot
+    /// dominate the listener
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let listener = BeaconListener::new("noisy".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener")
+    ///     .per_source_rate_limit(5);
+    ///
+    /// let beacon = BeaconSender::new(8080, "noisy".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// for _ in 0..20 {
+    ///     beacon.send_one_beacon().expect("Could not send beacon");
+    /// }
+    ///
+    /// let mut received = 0;
+    /// while listener.wait(Some(Duration::from_millis(200))).is_ok() {
+    ///     received += 1;
+    /// }
+    /// assert_eq!(received, 5);
+    /// assert_eq!(listener.rate_limited_count(), 15);
+    /// ```
+    pub fn per_source_rate_limit(mut self, max_per_sec: u32) -> Self {
+        self.rate_limiter = Some(Mutex::new(RateLimiter {
+            max_per_sec,
+            windows: HashMap::new(),
+        }));
+        self
+    }
+
+    /// Collapse the repeated beacons of chatty senders: once a beacon from a sender (identified by
+    /// its service IP, port and name) has been returned, further beacons from it are dropped until
+    /// `window` has passed, so each sender is returned at most once per window rather than its
+    /// repeats queuing up to be returned one after the other.
+    /// `wait_many()` and `scan()` keep the latest beacon returned from each sender. Keep the
+    /// window shorter than the TTL of a `ServiceRegistry` fed by the listener, or its services expire
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let listener = BeaconListener::new("chatty".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener")
+    ///     .with_debounce(Duration::from_millis(500));
+    ///
+    /// let mut beacon = BeaconSender::new(8080, "chatty".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// for _ in 0..5 {
+    ///     beacon.send_one_beacon().expect("Could not send beacon");
+    /// }
+    /// assert!(listener.wait(Some(Duration::from_secs(5))).is_ok());
+    /// assert!(listener.wait(Some(Duration::from_millis(200))).is_err());
+    ///
+    /// std::thread::sleep(Duration::from_millis(500));
+    /// beacon = beacon.with_generation(2);
+    /// beacon.send_one_beacon().expect("Could not send beacon");
+    /// let received = listener.wait(Some(Duration::from_secs(5))).expect("Failed to receive beacon");
+    /// assert_eq!(received.generation, Some(2));
+    /// ```
+    pub fn with_debounce(self, window: Duration) -> Self {
+        self.with_debounce_by(window, Beacon::sender_hash)
+    }
+
+    /// Collapse repeated beacons like `with_debounce()`, with beacons repeating each other if
+    /// `key` returns the same for them, rather than if they are from the same sender. With
+    /// `Beacon::content_hash()` as the key only beacons that say nothing new are dropped, so a
+    /// beacon whose metadata has changed is returned straight away, however recent the last one
+    ///
+    /// ```
+    /// use simpdiscoverylib::{Beacon, BeaconSender, BeaconListener};
+    /// use std::collections::HashMap;
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let listener = BeaconListener::new("changing".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener")
+    ///     .with_debounce_by(Duration::from_secs(60), Beacon::content_hash);
+    ///
+    /// let beacon = BeaconSender::new(8080, "changing".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// for _ in 0..3 {
+    ///     beacon.send_one_beacon().expect("Could not send beacon");
+    /// }
+    /// assert!(listener.wait(Some(Duration::from_secs(5))).is_ok());
+    /// assert!(listener.wait(Some(Duration::from_millis(200))).is_err());
+    ///
+    /// beacon.update_metadata(HashMap::from([("state".into(), "draining".into())]))
+    ///     .expect("Could not update metadata");
+    /// beacon.send_one_beacon().expect("Could not send beacon");
+    /// let received = listener.wait(Some(Duration::from_secs(5))).expect("Failed to receive beacon");
+    /// assert_eq!(received.metadata["state"], "draining");
+    /// ```
+    pub fn with_debounce_by<F>(mut self, window: Duration, key: F) -> Self
+        where F: Fn(&Beacon) -> u64 + Send + Sync + 'static {
+        self.debouncer = Some(Mutex::new(Debouncer {
+            window,
+            key: Box::new(key),
+            last_returned: HashMap::new(),
+        }));
+        self
+    }
+
+    /// The number of beacons dropped because their source exceeded the limit set with
+    /// `per_source_rate_limit()`, since the listener was created or `take_stats()` was last called
+    pub fn rate_limited_count(&self) -> u64 {
+        self.counters.dropped_rate_limited.load(Ordering::Relaxed)
+    }
+
+    /// Wait for a `Beacon` on the port specified in `BeaconListener::new()`
+    /// If `timeout` is None, then it will block forever waiting for a beacon matching the optional
+    /// filter (if supplied) in `BeaconListener::new()`. If no `filter` was supplied it will block
+    /// waiting for any beacon to be received.
+    ///
+    /// If `timeout` is `Some(Duration)` then it will block for that duration on the reception of
+    /// each beacon. If the beacon does not match a supplied `filter` then it will loop (blocking
+    /// for `duration` each time until a matching beacon is found. Use `wait_deadline()` to bound
+    /// the total time spent waiting instead.
+    ///
+    /// If it times out, the error is a `DiscoveryError::Timeout` with a `NoMatchReport` describing
+    /// the non-matching beacons that were seen, to help diagnose why the expected service wasn't found
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener, DiscoveryError};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let listener = BeaconListener::new("foo".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// let beacon = BeaconSender::new(8080, "bar".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// for _ in 0..3 {
+    ///     beacon.send_one_beacon().expect("Could not send beacon");
+    /// }
+    ///
+    /// let error = listener.wait(Some(Duration::from_millis(200))).err().expect("Expected a timeout");
+    /// let report = match &error {
+    ///     DiscoveryError::Timeout(report) => report,
+    ///     _ => panic!("Expected a NoMatchReport"),
+    /// };
+    /// assert_eq!(report.non_matching, 3);
+    /// assert_eq!(report.sources, 1);
+    /// assert_eq!(error.to_string(), "saw 3 beacons from 1 sources, none matched 'foo'");
+    /// ```
+    pub fn wait(&self, timeout: Option<Duration>) -> Result<Beacon> {
+        self.socket.set_read_timeout(timeout)?;
+        info!("{}Read timeout set to: {:?}", self.log_prefix(), timeout);
+
+        info!("{}Waiting for beacon matching '{}'", self.log_prefix(), self.filter);
+        self.wait_matching(|| Ok(()), |_| true)
+    }
+
+    /// Wait for a `Beacon` matching the service name of the listener like `wait()`, also returning
+    /// how long it took to arrive, e.g. to measure discovery latency
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let listener = BeaconListener::new("timed".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// std::thread::spawn(move || {
+    ///     std::thread::sleep(Duration::from_millis(200));
+    ///     BeaconSender::new(8080, "timed".as_bytes(), broadcast_port)
+    ///         .expect("Could not create sender")
+    ///         .send_one_beacon().expect("Could not send beacon");
+    /// });
+    ///
+    /// let (beacon, waited) = listener.wait_timed(Some(Duration::from_secs(5))).expect("Failed to receive beacon");
+    /// assert_eq!(beacon.service_port, 8080);
+    /// assert!(waited >= Duration::from_millis(200) && waited < Duration::from_secs(5));
+    /// ```
+    pub fn wait_timed(&self, timeout: Option<Duration>) -> Result<(Beacon, Duration)> {
+        let start = Instant::now();
+        let beacon = self.wait(timeout)?;
+        Ok((beacon, start.elapsed()))
+    }
+
+    /// Wait for a `Beacon` like `wait()`, returning the first that both matches the service name
+    /// of the listener and satisfies `predicate`, e.g. to only accept beacons with some metadata or
+    /// from some subnet. Use a listener created with `new_accept_all()` to match on `predicate` alone.
+    /// Beacons rejected by `predicate` are counted as non-matching in the stats and the timeout report
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::collections::HashMap;
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let listener = BeaconListener::new("regional".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    ///
+    /// for (service_port, region) in [(8080, "us"), (8081, "eu")] {
+    ///     let mut metadata = HashMap::new();
+    ///     metadata.insert("region".to_string(), region.to_string());
+    ///     BeaconSender::new(service_port, "regional".as_bytes(), broadcast_port)
+    ///         .expect("Could not create sender")
+    ///         .with_metadata(metadata).expect("Metadata is too large")
+    ///         .send_one_beacon().expect("Could not send beacon");
+    /// }
+    ///
+    /// let beacon = listener.wait_where(Some(Duration::from_secs(5)),
+    ///     |beacon| beacon.metadata.get("region").map(String::as_str) == Some("eu"))
+    ///     .expect("Failed to receive beacon");
+    /// assert_eq!(beacon.service_port, 8081);
+    /// ```
+    pub fn wait_where<F: Fn(&Beacon) -> bool>(&self, timeout: Option<Duration>, predicate: F) -> Result<Beacon> {
+        self.socket.set_read_timeout(timeout)?;
+        info!("{}Read timeout set to: {:?}", self.log_prefix(), timeout);
+
+        info!("{}Waiting for beacon matching '{}' and a predicate", self.log_prefix(), self.filter);
+        self.wait_matching(|| Ok(()), predicate)
+    }
+
+    /// Wait for a `Beacon` matching the service name of the listener like `wait()`, but returning
+    /// a `DiscoveryError::Timeout` no later than `deadline`, however many non-matching beacons or
+    /// other datagrams are received and discarded meanwhile
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::time::{Duration, Instant};
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let listener = BeaconListener::new("awaited".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// let flood = BeaconSender::new(8080, "other".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// std::thread::spawn(move || {
+    ///     flood.send_loop(Duration::from_millis(10)).expect("Could not enter send_loop");
+    /// });
+    ///
+    /// let start = Instant::now();
+    /// let error = listener.wait_deadline(start + Duration::from_millis(300)).err().expect("Expected a timeout");
+    /// assert!(error.is_timeout());
+    /// assert!(start.elapsed() < Duration::from_secs(1));
+    /// ```
+    pub fn wait_deadline(&self, deadline: Instant) -> Result<Beacon> {
+        info!("{}Waiting for beacon matching '{}' for at most {:?}", self.log_prefix(), self.filter,
+            deadline.saturating_duration_since(Instant::now()));
+        self.wait_matching(|| {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            self.socket.set_read_timeout(Some(remaining))
+        }, |_| true)
+    }
+
+    /*
+        Wait for a beacon matching the filter and `predicate`, calling `before_receive` before each
+        datagram is received, e.g. to set the read timeout
+     */
+    fn wait_matching<F, P>(&self, mut before_receive: F, predicate: P) -> Result<Beacon>
+        where F: FnMut() -> io::Result<()>, P: Fn(&Beacon) -> bool {
+        let mut unmatched = Unmatched::default();
+        loop {
+            let beacon = match self.receive_one_beacon(&mut before_receive) {
+                Ok(beacon) => beacon,
+                Err(e) if e.is_timeout() => return Err(DiscoveryError::Timeout(self.no_match_report(&unmatched))),
+                Err(e) => return Err(e),
+            };
+
+            if let Some(beacon) = self.filter_where(beacon, &mut unmatched, &predicate) {
+                if self.coalesce_bursts {
+                    return self.coalesce_burst(beacon, &predicate).map_err(DiscoveryError::Receive);
+                }
+                return Ok(beacon);
+            }
+        }
+    }
+
+    /// Return the next beacon matching the service name of the listener if one has already been
+    /// received, or `None` without blocking if not, e.g. to poll for beacons from an event loop.
+    /// Non-matching beacons already received are consumed. The socket is left in the blocking
+    /// mode it was in, except on Windows where the mode can't be read, so it is left blocking
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let listener = BeaconListener::new("polled".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// assert!(listener.try_receive().expect("Could not poll").is_none());
+    ///
+    /// let beacon = BeaconSender::new(8080, "polled".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// beacon.send_one_beacon().expect("Could not send beacon");
+    /// std::thread::sleep(Duration::from_millis(100));
+    ///
+    /// let beacon = listener.try_receive().expect("Could not poll").expect("No beacon ready");
+    /// assert_eq!(beacon.service_port, 8080);
+    ///
+    /// #[cfg(unix)]
+    /// {
+    ///     listener.socket().set_nonblocking(true).expect("Could not set non-blocking");
+    ///     assert!(listener.try_receive().expect("Could not poll").is_none());
+    ///     let error = listener.socket().recv_from(&mut [0; 16]).expect_err("Socket blocked");
+    ///     assert_eq!(error.kind(), std::io::ErrorKind::WouldBlock);
+    /// }
+    /// ```
+    pub fn try_receive(&self) -> Result<Option<Beacon>> {
+        let was_nonblocking = self.nonblocking()?;
+        self.socket.set_nonblocking(true)?;
+        let mut unmatched = Unmatched::default();
+        let result = loop {
+            match self.receive_one_beacon(&mut || Ok(())) {
+                Ok(beacon) => if let Some(beacon) = self.filter(beacon, &mut unmatched) {
+                    break Ok(Some(beacon));
+                },
+                Err(e) if e.is_timeout() => break Ok(None),
+                Err(e) => break Err(e),
+            }
+        };
+        self.socket.set_nonblocking(was_nonblocking)?;
+
+        match result {
+            Ok(Some(beacon)) if self.coalesce_bursts =>
+                self.coalesce_burst(beacon, |_| true).map(Some).map_err(DiscoveryError::Receive),
+            result => result,
+        }
+    }
+
+    /// An iterator over the beacons matching the service name of the listener, as they arrive.
+    /// Each call to `next()` blocks until the next one is received, as `wait(None)` does, so the
+    /// iterator never ends unless a receive error is returned and the caller stops on it.
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let listener = BeaconListener::new("streamed".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// let beacon = BeaconSender::new(8080, "streamed".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// std::thread::spawn(move || {
+    ///     beacon.send_loop(Duration::from_millis(50)).expect("Could not enter send_loop");
+    /// });
+    ///
+    /// let mut last_sequence = None;
+    /// for beacon in listener.incoming().take(3) {
+    ///     let beacon = beacon.expect("Failed to receive beacon");
+    ///     assert_eq!(beacon.service_port, 8080);
+    ///     // Each beacon from the sender carries the next sequence number
+    ///     assert!(last_sequence.is_none_or(|last| beacon.sequence > last));
+    ///     last_sequence = Some(beacon.sequence);
+    /// }
+    /// ```
+    pub fn incoming(&self) -> impl Iterator<Item = Result<Beacon>> + '_ {
+        std::iter::from_fn(move || Some(self.wait(None)))
+    }
+
+    /// Call `handler` with each beacon matching the service name of the listener as it arrives,
+    /// until `handler` returns `ControlFlow::Break(())`. If `timeout` is `Some(Duration)` then
+    /// it will block for that duration on the reception of each beacon, as `wait()` does, and
+    /// return its error if no matching beacon arrives in time.
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::ops::ControlFlow;
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let listener = BeaconListener::new("handled".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// let beacon = BeaconSender::new(8080, "handled".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// std::thread::spawn(move || {
+    ///     beacon.send_loop(Duration::from_millis(50)).expect("Could not enter send_loop");
+    /// });
+    ///
+    /// let mut count = 0;
+    /// listener.listen_with(Some(Duration::from_secs(5)), |beacon| {
+    ///     assert_eq!(beacon.service_port, 8080);
+    ///     count += 1;
+    ///     if count == 3 { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+    /// }).expect("Failed to receive beacons");
+    /// assert_eq!(count, 3);
+    /// ```
+    pub fn listen_with<F: FnMut(Beacon) -> ControlFlow<()>>(&self, timeout: Option<Duration>,
+                                                            mut handler: F) -> Result<()> {
+        loop {
+            let beacon = self.wait(timeout)?;
+            if handler(beacon).is_break() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Call `handler` with each beacon matching the service name of the listener like
+    /// `listen_with()`, on a pool of `threads` threads while the calling thread keeps receiving,
+    /// so slow handlers (e.g. ones looking up each service) don't hold up reception. Up to
+    /// `queue_bound` beacons wait for a free thread; when the queue is full the calling thread
+    /// stops receiving until there is room, leaving further datagrams queued in the socket.
+    /// Once `handler` returns `ControlFlow::Break(())` reception stops at the next beacon or
+    /// timeout; beacons already queued are still handled before this returns
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::ops::ControlFlow;
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let listener = BeaconListener::new("pooled".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// let beacon = BeaconSender::new(8080, "pooled".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// std::thread::spawn(move || {
+    ///     beacon.send_loop(Duration::from_millis(20)).expect("Could not enter send_loop");
+    /// });
+    ///
+    /// let handled = AtomicUsize::new(0);
+    /// listener.listen_with_pool(Some(Duration::from_secs(5)), 4, 8, |beacon| {
+    ///     assert_eq!(beacon.service_port, 8080);
+    ///     std::thread::sleep(Duration::from_millis(100));
+    ///     let count = handled.fetch_add(1, Ordering::SeqCst) + 1;
+    ///     if count >= 8 { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+    /// }).expect("Failed to receive beacons");
+    /// assert!(handled.load(Ordering::SeqCst) >= 8);
+    /// ```
+    pub fn listen_with_pool<F>(&self, timeout: Option<Duration>, threads: usize, queue_bound: usize,
+                               handler: F) -> Result<()>
+        where F: Fn(Beacon) -> ControlFlow<()> + Sync {
+        let stop = AtomicBool::new(false);
+        let (sender, receiver) = mpsc::sync_channel(queue_bound);
+        let receiver = Mutex::new(receiver);
+
+        std::thread::scope(|scope| {
+            for _ in 0..threads.max(1) {
+                scope.spawn(|| loop {
+                    // The lock is released before handling, so the other threads can take the next
+                    let next = receiver.lock().unwrap_or_else(|e| e.into_inner()).recv();
+                    match next {
+                        Ok(beacon) => if handler(beacon).is_break() {
+                            stop.store(true, Ordering::Relaxed);
+                        },
+                        Err(_) => break,
+                    }
+                });
+            }
+
+            let result = loop {
+                if stop.load(Ordering::Relaxed) {
+                    break Ok(());
+                }
+                match self.wait(timeout) {
+                    Ok(beacon) => if sender.send(beacon).is_err() {
+                        break Ok(());
+                    },
+                    Err(e) => break Err(e),
+                }
+            };
+            // Disconnect the queue, so the threads stop once it is empty
+            drop(sender);
+            result
+        })
+    }
+
+    /// Move the listener to a new thread that forwards each beacon matching its service name to
+    /// the channel returned, to receive them in another thread's loop. The thread stops at the
+    /// next matching beacon after the `Receiver` is dropped, or when receiving fails (logging
+    /// the error), which disconnects the channel
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let (thread, beacons) = BeaconListener::new("channelled".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener")
+    ///     .into_channel();
+    /// let beacon = BeaconSender::new(8080, "channelled".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// std::thread::spawn(move || {
+    ///     beacon.send_loop(Duration::from_millis(50)).expect("Could not enter send_loop");
+    /// });
+    ///
+    /// let beacon = beacons.recv_timeout(Duration::from_secs(5)).expect("Failed to receive beacon");
+    /// assert_eq!(beacon.service_port, 8080);
+    ///
+    /// drop(beacons);
+    /// thread.join().expect("Listening thread panicked");
+    /// ```
+    pub fn into_channel(self) -> (JoinHandle<()>, Receiver<Beacon>) {
+        let (sender, receiver) = mpsc::channel();
+        let thread = std::thread::spawn(move || {
+            let result = self.listen_with(None, |beacon| match sender.send(beacon) {
+                Ok(()) => ControlFlow::Continue(()),
+                Err(_) => ControlFlow::Break(()),
+            });
+            if let Err(e) = result {
+                warn!("{}Stopped forwarding beacons to the channel ({e})", self.log_prefix());
+            }
+        });
+        (thread, receiver)
+    }
+
+    /// Collect all the beacons matching the service name of the listener that arrive within
+    /// `timeout` (in total, not per beacon), e.g. to find all instances of a service on the LAN.
+    /// Each sender appears once, deduplicated by its `(service_ip, service_port)`, with the first
+    /// beacon received from it (or the latest if the listener was set `with_debounce()`). If
+    /// `max` is `Some(n)` it returns as soon as `n` have been found.
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let listener = BeaconListener::new("scanned".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// for service_port in [8080, 8081] {
+    ///     let beacon = BeaconSender::new(service_port, "scanned".as_bytes(), broadcast_port)
+    ///         .expect("Could not create sender");
+    ///     beacon.send_one_beacon().expect("Could not send beacon");
+    ///     beacon.send_one_beacon().expect("Could not send beacon");
+    /// }
+    ///
+    /// let beacons = listener.wait_many(Duration::from_millis(300), None).expect("Failed to scan");
+    /// assert_eq!(beacons.len(), 2);
+    /// ```
+    pub fn wait_many(&self, timeout: Duration, max: Option<usize>) -> Result<Vec<Beacon>> {
+        self.collect(timeout, max, |_| {})
+    }
+
+    /// Scan for the beacons matching the service name of the listener for `total`, like
+    /// `wait_many()`, calling `on_new` as each new sender is first seen (e.g. so a UI can be
+    /// updated live) and returning all the senders seen when `total` has passed
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::time::{Duration, Instant};
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let listener = BeaconListener::new("surveyed".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// for service_port in [8080, 8081] {
+    ///     let beacon = BeaconSender::new(service_port, "surveyed".as_bytes(), broadcast_port)
+    ///         .expect("Could not create sender");
+    ///     beacon.send_one_beacon().expect("Could not send beacon");
+    ///     beacon.send_one_beacon().expect("Could not send beacon");
+    /// }
+    ///
+    /// let start = Instant::now();
+    /// let mut sightings = vec![];
+    /// let beacons = listener.scan(Duration::from_millis(300), |beacon| sightings.push(beacon.service_port))
+    ///     .expect("Failed to scan");
+    /// assert!(start.elapsed() >= Duration::from_millis(300));
+    /// assert_eq!(beacons.len(), 2);
+    /// sightings.sort();
+    /// assert_eq!(sightings, vec![8080, 8081]);
+    /// ```
+    pub fn scan<F: FnMut(&Beacon)>(&self, total: Duration, on_new: F) -> Result<Vec<Beacon>> {
+        self.collect(total, None, on_new)
+    }
+
+    /*
+        Collect the beacons from each sender matching the filter within `timeout`, up to `max`,
+        calling `on_new` with each as it is first seen
+     */
+    fn collect<F: FnMut(&Beacon)>(&self, timeout: Duration, max: Option<usize>, mut on_new: F) -> Result<Vec<Beacon>> {
+        info!("{}Collecting beacons matching '{}' for {:?}", self.log_prefix(),
+            self.filter, timeout);
+        let mut buffer = self.receive_buffer();
+        let mut unmatched = Unmatched::default();
+        let mut seen = HashSet::new();
+        let mut beacons = vec![];
+        let deadline = Instant::now() + timeout;
+
+        while max.is_none_or(|max| beacons.len() < max) {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            self.socket.set_read_timeout(Some(remaining))?;
+
+            let (number_of_bytes, source_address, received_on) = match self.receive_datagram(&mut buffer) {
+                Ok(received) => received,
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => break,
+                Err(e) => return Err(DiscoveryError::Receive(e)),
+            };
+
+            if let Some(mut beacon) = self.accept(&buffer[..number_of_bytes], source_address)
+                .and_then(|beacon| self.filter(beacon, &mut unmatched)) {
+                beacon.received_on = received_on;
+                let sender = (beacon.service_ip.clone(), beacon.service_port);
+                if seen.insert(sender.clone()) {
+                    on_new(&beacon);
+                    beacons.push(beacon);
+                } else if self.debouncer.is_some() {
+                    // Replace the earlier beacon from the sender with its latest
+                    if let Some(earlier) = beacons.iter_mut()
+                        .find(|earlier| (&earlier.service_ip, earlier.service_port) == (&sender.0, sender.1)) {
+                        *earlier = beacon;
+                    }
+                }
+            }
+        }
+
+        info!("{}Found {} instances of '{}'", self.log_prefix(), beacons.len(), self.filter);
+        Ok(beacons)
+    }
+
+    /*
+        Return `beacon` if it matches the service name filter, otherwise record it in `unmatched`
+     */
+    fn filter(&self, beacon: Beacon, unmatched: &mut Unmatched) -> Option<Beacon> {
+        self.filter_where(beacon, unmatched, |_| true)
+    }
+
+    /*
+        Return `beacon` if it matches the filter and `predicate`, otherwise count it as unmatched
+     */
+    fn filter_where<P: Fn(&Beacon) -> bool>(&self, beacon: Beacon, unmatched: &mut Unmatched,
+                                            predicate: P) -> Option<Beacon> {
+        let matched = self.filter.matches(&beacon.service_name) && predicate(&beacon);
+        self.record(&beacon, matched);
+        if matched {
+            if !self.debounced(&beacon) {
+                trace!("{}Beacon '{}' from '{}' repeats one within the debounce window: ignoring",
+                    self.log_prefix(), beacon.service_name_str(), beacon.source_addr);
+                return None;
+            }
+            trace!("{}Beacon '{}' matches filter '{}': returning beacon", self.log_prefix(),
+                beacon.service_name_str(), self.filter);
+            increment(&self.counters.matched);
+            Some(beacon)
+        } else {
+            trace!("{}Beacon '{}' does not match filter '{}': ignoring", self.log_prefix(),
+                beacon.service_name_str(), self.filter);
+            increment(&self.counters.non_matching);
+            unmatched.beacons += 1;
+            unmatched.sources.insert(beacon.service_ip);
+            None
+        }
+    }
+
+    fn no_match_report(&self, unmatched: &Unmatched) -> NoMatchReport {
+        let report = NoMatchReport {
+            service_name: self.filter.to_string().into_bytes(),
+            non_matching: unmatched.beacons,
+            sources: unmatched.sources.len(),
+        };
+        info!("{}Timed out: {}", self.log_prefix(), report);
+        report
+    }
+
+    /// Receive one raw datagram into the caller supplied `buffer`, returning the number of bytes
+    /// received and the address it was sent from. Use `Beacon::parse_in_place()` to parse it.
+    ///
+    /// This gives full control over buffer allocation when processing very high beacon volumes.
+    /// It blocks using the read timeout most recently set (e.g. by `wait()`), or forever if
+    /// none has been set. If `buffer` is too small to hold the datagram the excess is discarded.
+    /// Receives interrupted by a signal are retried, and if the timeout expires the error returned
+    /// is a `DiscoveryError::Receive` for which `is_timeout()` is `true`
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconListener, DiscoveryError};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let port = pick_unused_port().expect("Could not get a free port");
+    /// let listener = BeaconListener::new("raw".as_bytes(), port).expect("Could not create listener");
+    /// // Times out, leaving the read timeout set for `recv_into()`
+    /// assert!(matches!(listener.wait(Some(Duration::from_millis(50))), Err(DiscoveryError::Timeout(_))));
+    ///
+    /// let mut buffer = [0; 1024];
+    /// match listener.recv_into(&mut buffer) {
+    ///     Err(e) => assert!(e.is_timeout()),
+    ///     Ok(_) => panic!("Nothing was sent"),
+    /// }
+    /// ```
+    pub fn recv_into(&self, buffer: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        retry_interrupted(|| self.socket.recv_from(buffer)).map_err(DiscoveryError::Receive)
+    }
+
+    /*
+        Receive a datagram into `buffer`, with the local address it was received on if known,
+        retrying if interrupted by a signal
+     */
+    fn receive_datagram(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<Ipv4Addr>)> {
+        retry_interrupted(|| self.receive_datagram_once(buffer))
+    }
+
+    #[cfg(all(feature = "packet-info", target_os = "linux"))]
+    fn receive_datagram_once(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<Ipv4Addr>)> {
+        if self.packet_info {
+            packet_info::recv_from(&self.socket, buffer)
+        } else {
+            self.socket.recv_from(buffer).map(|(length, source_address)| (length, source_address, None))
+        }
+    }
+
+    #[cfg(not(all(feature = "packet-info", target_os = "linux")))]
+    fn receive_datagram_once(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<Ipv4Addr>)> {
+        self.socket.recv_from(buffer).map(|(length, source_address)| (length, source_address, None))
+    }
+
+    /*
+        Whether the socket is in non-blocking mode, to restore it after receiving without
+        blocking. Windows can't report the mode, so the socket is taken to be blocking, as it is
+        unless the caller changed it through `socket()`
+     */
+    #[cfg(unix)]
+    fn nonblocking(&self) -> io::Result<bool> {
+        socket2::SockRef::from(&self.socket).nonblocking()
+    }
+
+    #[cfg(not(unix))]
+    fn nonblocking(&self) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    /*
+        Replace `beacon` with any more recent beacons from the same sender that are already waiting
+        to be received, without blocking and without consuming beacons from other senders. Each
+        one is accepted and filtered (with `predicate`) as it would be if received on its own
+     */
+    fn coalesce_burst<P: Fn(&Beacon) -> bool>(&self, mut beacon: Beacon, predicate: P) -> io::Result<Beacon> {
+        let mut buffer = self.receive_buffer();
+        let was_nonblocking = self.nonblocking()?;
+        self.socket.set_nonblocking(true)?;
+
+        let result = loop {
+            match self.socket.peek_from(&mut buffer) {
+                Ok((number_of_bytes, source_address)) => {
+                    match self.parse(&buffer[..number_of_bytes], source_address) {
+                        Some(peeked) if peeked.service_ip == beacon.service_ip &&
+                            peeked.service_port == beacon.service_port &&
+                            peeked.service_name == beacon.service_name => {
+                            trace!("{}Coalescing burst beacon from '{}'", self.log_prefix(), source_address);
+                            let (number_of_bytes, source_address, received_on) =
+                                match self.receive_datagram(&mut buffer) {
+                                    Ok(received) => received,
+                                    Err(e) => break Err(e),
+                                };
+                            let next = self.accept(&buffer[..number_of_bytes], source_address)
+                                .map(|next| Beacon { received_on, ..next })
+                                .and_then(|next| self.filter_where(next, &mut Unmatched::default(), &predicate));
+                            if let Some(next) = next {
+                                beacon = next;
+                            }
+                        }
+                        _ => break Ok(beacon),
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(beacon),
+                Err(e) => break Err(e),
+            }
+        };
+
+        self.socket.set_nonblocking(was_nonblocking)?;
+        result
+    }
+
+    /*
+        Parse `payload` into a `Beacon` if it is one signed with the verification key (if any),
+        keeping the payload if raw payloads are being captured. Unlike `accept()` nothing is counted
+        and the rate limit etc. are not applied, to look at a datagram before receiving it
+     */
+    pub(crate) fn parse(&self, payload: &[u8], source_address: SocketAddr) -> Option<Beacon> {
+        Beacon::parse_with_magic_number(payload, source_address, self.magic_number)
+            .filter(|_| self.verified(payload))
+            .map(|beacon| self.captured(beacon, payload))
+    }
+
+    /*
+        Whether the beacon in `payload` is signed with the verification key, if there is one
+     */
+    #[cfg(feature = "signed-beacons")]
+    fn verified(&self, payload: &[u8]) -> bool {
+        self.verification_key.as_ref().is_none_or(|key| signing::verify(payload, key))
+    }
+
+    #[cfg(not(feature = "signed-beacons"))]
+    fn verified(&self, _payload: &[u8]) -> bool {
+        true
+    }
+
+    fn captured(&self, mut beacon: Beacon, payload: &[u8]) -> Beacon {
+        if self.capture_raw_payloads {
+            beacon.raw_payload = Some(payload.to_vec());
+        }
+        beacon
+    }
+
+    fn within_rate_limit(&self, source: IpAddr) -> bool {
+        match &self.rate_limiter {
+            Some(limiter) => limiter.lock().unwrap_or_else(|e| e.into_inner()).allow(source),
+            None => true,
+        }
+    }
+
+    /*
+        Whether a matching `beacon` should be returned, rather than dropped as a repeat within
+        the debounce window
+     */
+    fn debounced(&self, beacon: &Beacon) -> bool {
+        match &self.debouncer {
+            Some(debouncer) => debouncer.lock().unwrap_or_else(|e| e.into_inner()).allow(beacon),
+            None => true,
+        }
+    }
+
+    /*
+        Receive one beacon, giving up if more datagrams than allowed are dropped first
+     */
+    fn receive_one_beacon<F: FnMut() -> io::Result<()>>(&self, before_receive: &mut F) -> Result<Beacon> {
+        let mut buffer = self.receive_buffer();
+        let mut dropped = 0;
+
+        loop {
+            before_receive().map_err(DiscoveryError::Receive)?;
+            let (number_of_bytes, source_address, received_on) = self.receive_datagram(&mut buffer)
+                .map_err(DiscoveryError::Receive)?;
+            if let Some(mut beacon) = self.accept(&buffer[..number_of_bytes], source_address) {
+                beacon.received_on = received_on;
+                return Ok(beacon);
+            }
+
+            dropped += 1;
+            if self.max_dropped.is_some_and(|max| dropped >= max) {
+                return Err(DiscoveryError::MalformedBeacon(
+                    format!("{dropped} datagrams in a row were dropped, the last from '{source_address}'")));
+            }
+        }
+    }
+
+    /*
+        Parse a received datagram into a `Beacon` if it is one and is not dropped by the
+        rate limit, allowed port range or schema
+     */
+    fn accept(&self, payload: &[u8], source_address: SocketAddr) -> Option<Beacon> {
+        increment(&self.counters.received);
+        if payload.len() > self.max_beacon_size {
+            warn!("{}Datagram from '{}' is larger than the largest beacon and was truncated: dropping",
+                self.log_prefix(), source_address);
+            increment(&self.counters.dropped_truncated);
+            return None;
+        }
+
+        let beacon = match Beacon::parse_with_magic_number(payload, source_address, self.magic_number) {
+            Some(beacon) => self.captured(beacon, payload),
+            None => {
+                increment(&self.counters.dropped_invalid);
+                return None;
+            }
+        };
+
+        if !self.verified(payload) {
+            trace!("{}Beacon from '{}' is not signed with the verification key: dropping", self.log_prefix(),
+                source_address);
+            increment(&self.counters.dropped_unverified);
+            self.record(&beacon, false);
+            return None;
+        }
+
+        if !self.within_rate_limit(source_address.ip()) {
+            trace!("{}Beacon from '{}' exceeds the per source rate limit: dropping", self.log_prefix(), source_address.ip());
+            increment(&self.counters.dropped_rate_limited);
+            self.record(&beacon, false);
+            return None;
+        }
+
+        if let Some(ports) = &self.allowed_ports {
+            if !ports.contains(&beacon.service_port) {
+                trace!("{}Beacon from '{}' has service port {} outside the allowed range: dropping", self.log_prefix(),
+                    source_address.ip(), beacon.service_port);
+                increment(&self.counters.dropped_port);
+                self.record(&beacon, false);
+                return None;
+            }
+        }
+
+        if let Some(reason) = self.schema.as_ref().and_then(|schema| schema.violation(&beacon.metadata)) {
+            warn!("{}Beacon from '{}' doesn't conform to the schema as {reason}: dropping", self.log_prefix(),
+                source_address.ip());
+            increment(&self.counters.dropped_schema);
+            self.record(&beacon, false);
+            return None;
+        }
+
+        Some(beacon)
+    }
+}
+
+/*
+    The beacons seen by a wait that did not match, for reporting if it times out
+ */
+#[derive(Default)]
+struct Unmatched {
+    beacons: usize,
+    sources: HashSet<String>,
+}
+
+impl Drop for BeaconListener {
+    fn drop(&mut self) {
+        let left = match self.multicast_group {
+            Some(MulticastGroup::V4(group)) => {
+                trace!("{}Leaving multicast group: {}", self.log_prefix(), group);
+                self.socket.leave_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)
+            }
+            Some(MulticastGroup::V6(group, scope_id)) => {
+                trace!("{}Leaving multicast group: {}", self.log_prefix(), group);
+                self.socket.leave_multicast_v6(&group, scope_id)
+            }
+            None => Ok(()),
+        };
+        if let Err(e) = left {
+            warn!("{}Could not leave multicast group ({})", self.log_prefix(), e);
+        }
+        trace!("{}Dropping listener for '{}' bound to: {:?}", self.log_prefix(), self.filter,
+            self.socket.local_addr());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jittered_stays_within_jitter_of_period() {
+        let period = Duration::from_secs(10);
+        let jitter = Duration::from_secs(2);
+        let jitter_nanos = jitter.as_nanos() as u64;
+        assert_eq!(jittered(period, jitter, 0), period - jitter);
+        assert_eq!(jittered(period, jitter, jitter_nanos), period);
+        assert_eq!(jittered(period, jitter, 2 * jitter_nanos), period + jitter);
+        for random in [1, jitter_nanos / 2, u64::MAX / 2, u64::MAX - 1, u64::MAX] {
+            let delay = jittered(period, jitter, random);
+            assert!(delay >= period - jitter && delay <= period + jitter, "{:?} from {}", delay, random);
+        }
+    }
+
+    #[test]
+    fn jittered_saturates_when_jitter_exceeds_period() {
+        let period = Duration::from_secs(1);
+        let jitter = Duration::from_secs(5);
+        assert_eq!(jittered(period, jitter, 0), Duration::ZERO);
+        for random in [1, u64::MAX / 2, u64::MAX] {
+            assert!(jittered(period, jitter, random) <= period + jitter);
+        }
+        let upper = 2 * jitter.as_nanos() as u64;
+        assert_eq!(jittered(Duration::MAX, jitter, upper), Duration::MAX - jitter);
+        assert_eq!(jittered(period, Duration::ZERO, u64::MAX), period);
+    }
+
+    #[test]
+    fn retry_interrupted_retries_until_received() {
+        let mut attempts = 0;
+        let received = retry_interrupted(|| {
+            attempts += 1;
+            if attempts <= 3 { Err(io::ErrorKind::Interrupted.into()) } else { Ok(attempts) }
+        });
+        assert_eq!(received.expect("Interrupted receive was not retried"), 4);
+    }
+
+    #[test]
+    fn retry_interrupted_returns_timeouts() {
+        for kind in [io::ErrorKind::WouldBlock, io::ErrorKind::TimedOut] {
+            let mut attempts = 0;
+            let error = retry_interrupted(|| -> io::Result<()> {
+                attempts += 1;
+                Err(kind.into())
+            }).expect_err("Timeout was not returned");
+            assert_eq!(attempts, 1);
+            assert!(DiscoveryError::Receive(error).is_timeout());
         }
     }
 }
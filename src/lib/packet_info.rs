@@ -0,0 +1,69 @@
+//! Receiving datagrams along with the local address they were received on, using `IP_PKTINFO`
+//! control messages, so a listener on a multi-homed host can report the interface that
+//! delivered each beacon (see `Beacon::received_on`)
+
+use std::io;
+use std::mem::{size_of, zeroed};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::os::unix::io::AsRawFd;
+
+/*
+    Ask the OS to attach an `IP_PKTINFO` control message to each datagram received on `socket`
+ */
+pub(crate) fn enable(socket: &UdpSocket) -> io::Result<()> {
+    let enabled: libc::c_int = 1;
+    // SAFETY: the option value points to a `c_int` that outlives the call, with its size
+    let result = unsafe {
+        libc::setsockopt(socket.as_raw_fd(), libc::IPPROTO_IP, libc::IP_PKTINFO,
+                         &enabled as *const libc::c_int as *const libc::c_void,
+                         size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if result == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+/*
+    Receive a datagram into `buffer` like `UdpSocket::recv_from()`, also returning the local
+    address it was received on if the OS attached an `IP_PKTINFO` control message
+ */
+pub(crate) fn recv_from(socket: &UdpSocket, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<Ipv4Addr>)> {
+    // Aligned for the `cmsghdr`s the OS writes into it, with room for several control messages
+    let mut control = [0u64; 16];
+    // SAFETY: zeroed is a valid value for these plain C structs
+    let mut source: libc::sockaddr_in = unsafe { zeroed() };
+    let mut iov = libc::iovec {
+        iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buffer.len(),
+    };
+    let mut message: libc::msghdr = unsafe { zeroed() };
+    message.msg_name = &mut source as *mut libc::sockaddr_in as *mut libc::c_void;
+    message.msg_namelen = size_of::<libc::sockaddr_in>() as libc::socklen_t;
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    message.msg_controllen = size_of::<[u64; 16]>() as _;
+
+    // SAFETY: every pointer in `message` refers to a live buffer of the length given with it
+    let received = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut message, 0) };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let source_address = SocketAddr::V4(SocketAddrV4::new(
+        Ipv4Addr::from(u32::from_be(source.sin_addr.s_addr)), u16::from_be(source.sin_port)));
+
+    let mut received_on = None;
+    // SAFETY: the control messages were written by the OS within `msg_controllen` of `control`,
+    // and the `CMSG_` macros only walk within it
+    unsafe {
+        let mut header = libc::CMSG_FIRSTHDR(&message);
+        while !header.is_null() {
+            if (*header).cmsg_level == libc::IPPROTO_IP && (*header).cmsg_type == libc::IP_PKTINFO {
+                let info = std::ptr::read_unaligned(libc::CMSG_DATA(header) as *const libc::in_pktinfo);
+                received_on = Some(Ipv4Addr::from(u32::from_be(info.ipi_spec_dst.s_addr)));
+            }
+            header = libc::CMSG_NXTHDR(&message, header);
+        }
+    }
+
+    Ok((received as usize, source_address, received_on))
+}
@@ -0,0 +1,245 @@
+use std::collections::HashSet;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use log::{info, trace, warn};
+use crate::{append_checksum, append_field, retry_interrupted, u16_to_array_of_u8, Beacon, BeaconListener,
+            BeaconSender, DatagramKind, DiscoveryError, Result, SendReport, ACK_FIELD, BROADCAST_ADDRESS,
+            LISTENING_ADDRESS, MAGIC_NUMBER, MAX_INCOMING_BEACON_SIZE, PROTOCOL_VERSION, QUERY_FIELD,
+            RECEIVE_BUFFER_SIZE};
+
+impl BeaconListener {
+    /// Answer queries from `query_services()` for the listener's service name forever, by sending
+    /// `sender`'s beacon directly to each client that asks. Services that respond to queries don't
+    /// need to send beacons periodically, cutting the discovery traffic on the network while idle.
+    /// A response that can't be sent to a client is logged and the next query is answered; it
+    /// only returns if receiving queries fails
+    ///
+    /// ```
+    /// use simpdiscoverylib::{query_services, BeaconSender, BeaconListener};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let query_port = pick_unused_port().expect("Could not get a free port for queries");
+    /// let listener = BeaconListener::new("queried".as_bytes(), query_port)
+    ///     .expect("Could not create listener");
+    /// let sender = BeaconSender::new(8080, "queried".as_bytes(), query_port)
+    ///     .expect("Could not create sender");
+    /// std::thread::spawn(move || listener.respond_to_queries(&sender).expect("Could not respond"));
+    ///
+    /// let beacons = query_services("queried".as_bytes(), query_port, Duration::from_millis(500))
+    ///     .expect("Could not query");
+    /// assert_eq!(beacons.len(), 1);
+    /// assert_eq!(beacons[0].service_port, 8080);
+    /// ```
+    pub fn respond_to_queries(&self, sender: &BeaconSender) -> Result<()> {
+        let mut buffer = self.receive_buffer();
+        self.socket.set_read_timeout(None)?;
+        info!("{}Responding to queries for '{}'", self.log_prefix(), self.filter);
+
+        loop {
+            let (number_of_bytes, source_address) = self.recv_into(&mut buffer)?;
+            if let Some((query, DatagramKind::Query)) = Beacon::parse_datagram(&buffer[..number_of_bytes], source_address,
+                                                                  self.magic_number) {
+                if self.filter.matches(&query.service_name) {
+                    trace!("{}Responding to query from '{}'", self.log_prefix(), source_address);
+                    // One unreachable client must not stop the other clients being answered
+                    if let Err(e) = sender.announce_to(source_address) {
+                        warn!("{}Could not respond to query from '{}' ({e})", self.log_prefix(), source_address);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Acknowledge `beacon`, received by the listener, by sending an ack directly back to the
+    /// socket it was sent from, e.g. for a coordinator to tell a node it has registered and
+    /// can stop announcing itself (see `BeaconSender::send_loop_until_acked()`)
+    pub fn acknowledge(&self, beacon: &Beacon) -> Result<SendReport> {
+        let ack = control_datagram(self.magic_number, beacon.service_port, &beacon.service_name, ACK_FIELD);
+        trace!("{}Acknowledging beacon from '{}'", self.log_prefix(), beacon.source_addr);
+        let bytes = self.socket.send_to(&ack, beacon.source_addr).map_err(DiscoveryError::Send)?;
+        Ok(SendReport { bytes, destination: beacon.source_addr })
+    }
+}
+
+impl BeaconSender {
+    /// Send beacons every `period` like `send_loop()`, until a listener acknowledges one (see
+    /// `BeaconListener::acknowledge()`) or `timeout` has passed, returning whether it was
+    /// acknowledged. Acks are received on the sender's socket between sends, so senders sharing
+    /// a fixed port (see `BeaconSender::new_with_bind_port()`) may not receive the acks meant for them
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let coordinator = BeaconListener::new("registering".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// std::thread::spawn(move || {
+    ///     let beacon = coordinator.wait(None).expect("Failed to receive beacon");
+    ///     coordinator.acknowledge(&beacon).expect("Could not acknowledge beacon");
+    /// });
+    ///
+    /// let sender = BeaconSender::new(8080, "registering".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// assert!(sender.send_loop_until_acked(Duration::from_millis(50), Duration::from_secs(5))
+    ///     .expect("Could not send beacons"));
+    ///
+    /// let unacknowledged = BeaconSender::new(8080, "unheard".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// assert!(!unacknowledged.send_loop_until_acked(Duration::from_millis(50), Duration::from_millis(300))
+    ///     .expect("Could not send beacons"));
+    /// ```
+    pub fn send_loop_until_acked(&self, period: Duration, timeout: Duration) -> Result<bool> {
+        let previous_timeout = self.socket.read_timeout()?;
+        let acked = self.send_until_acked(period, Instant::now() + timeout);
+        self.socket.set_read_timeout(previous_timeout)?;
+        acked
+    }
+
+    fn send_until_acked(&self, period: Duration, deadline: Instant) -> Result<bool> {
+        let mut buffer = [0; RECEIVE_BUFFER_SIZE];
+        loop {
+            if self.ready() {
+                self.send_one_beacon()?;
+            } else {
+                trace!("{}Service '{}' is not ready: not sending beacon", self.log_prefix(),
+                    String::from_utf8_lossy(&self.service_name));
+            }
+
+            let next_send = (Instant::now() + period).min(deadline);
+            loop {
+                let remaining = next_send.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    break;
+                }
+                self.socket.set_read_timeout(Some(remaining))?;
+
+                let (number_of_bytes, source_address) = match retry_interrupted(|| self.socket.recv_from(&mut buffer)) {
+                    Ok(received) => received,
+                    Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => break,
+                    Err(e) => return Err(DiscoveryError::Receive(e)),
+                };
+                if self.acknowledged_by(&buffer[..number_of_bytes], source_address) {
+                    info!("{}Beacon for '{}' was acknowledged by '{}'", self.log_prefix(),
+                        String::from_utf8_lossy(&self.service_name), source_address);
+                    return Ok(true);
+                }
+            }
+
+            if Instant::now() >= deadline {
+                return Ok(false);
+            }
+        }
+    }
+
+    /*
+        Whether `payload` is an ack of the sender's beacons
+     */
+    fn acknowledged_by(&self, payload: &[u8], source_address: SocketAddr) -> bool {
+        match Beacon::parse_datagram(payload, source_address, self.magic_number) {
+            Some((ack, DatagramKind::Ack)) => ack.service_name == self.service_name &&
+                ack.service_port == self.service_port.load(Ordering::Relaxed),
+            _ => false,
+        }
+    }
+}
+
+/*
+    A datagram with a beacon header for `service_name` and `service_port`, and the single empty
+    field `tag` saying what it is instead of a beacon
+ */
+fn control_datagram(magic_number: u16, service_port: u16, service_name: &[u8], tag: u8) -> Vec<u8> {
+    let mut datagram = u16_to_array_of_u8(magic_number).to_vec();
+    datagram.push(PROTOCOL_VERSION);
+    datagram.extend_from_slice(&u16_to_array_of_u8(service_port));
+    datagram.extend_from_slice(&u16_to_array_of_u8(service_name.len() as u16));
+    datagram.extend_from_slice(service_name);
+    append_field(&mut datagram, tag, &[]);
+    append_checksum(&mut datagram);
+    datagram
+}
+
+/// Broadcast a query for `service_name` on `query_port` and collect the beacons sent back by the
+/// services responding to queries there (see `BeaconListener::respond_to_queries()`) within
+/// `timeout` (in total). Each service appears once, deduplicated by its `(service_ip, service_port)`
+pub fn query_services(service_name: &[u8], query_port: u16, timeout: Duration) -> Result<Vec<Beacon>> {
+    query_services_with_magic_number(service_name, query_port, timeout, MAGIC_NUMBER)
+}
+
+/// Query services like `query_services()`, with queries and responses starting with
+/// `magic_number` rather than the default, for services whose listener and sender were set to
+/// it with `with_magic_number()`
+///
+/// ```
+/// use simpdiscoverylib::{query_services, query_services_with_magic_number, BeaconSender, BeaconListener};
+/// use std::time::Duration;
+/// use portpicker::pick_unused_port;
+///
+/// let query_port = pick_unused_port().expect("Could not get a free port for queries");
+/// let listener = BeaconListener::new("namespaced-query".as_bytes(), query_port)
+///     .expect("Could not create listener")
+///     .with_magic_number(0x5d15);
+/// let sender = BeaconSender::new(8080, "namespaced-query".as_bytes(), query_port)
+///     .expect("Could not create sender")
+///     .with_magic_number(0x5d15);
+/// std::thread::spawn(move || listener.respond_to_queries(&sender).expect("Could not respond"));
+///
+/// let beacons = query_services_with_magic_number("namespaced-query".as_bytes(), query_port,
+///                                                Duration::from_millis(500), 0x5d15)
+///     .expect("Could not query");
+/// assert_eq!(beacons.len(), 1);
+/// assert!(query_services("namespaced-query".as_bytes(), query_port, Duration::from_millis(200))
+///     .expect("Could not query").is_empty());
+/// ```
+pub fn query_services_with_magic_number(service_name: &[u8], query_port: u16, timeout: Duration,
+                                        magic_number: u16) -> Result<Vec<Beacon>> {
+    let bind_address = format!("{LISTENING_ADDRESS}:0");
+    let socket = UdpSocket::bind(&bind_address)
+        .map_err(|source| DiscoveryError::Bind { address: bind_address, source })?;
+    socket.set_broadcast(true).map_err(DiscoveryError::Broadcast)?;
+
+    let query = control_datagram(magic_number, 0, service_name, QUERY_FIELD);
+
+    let query_address = format!("{BROADCAST_ADDRESS}:{query_port}");
+    trace!("Sending query for '{}' to: '{}'", String::from_utf8_lossy(service_name), query_address);
+    socket.send_to(&query, &query_address).map_err(DiscoveryError::Send)?;
+
+    let mut buffer = [0; RECEIVE_BUFFER_SIZE];
+    let mut seen = HashSet::new();
+    let mut beacons = vec![];
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        socket.set_read_timeout(Some(remaining))?;
+
+        let (number_of_bytes, source_address) = match retry_interrupted(|| socket.recv_from(&mut buffer)) {
+            Ok(received) => received,
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => break,
+            Err(e) => return Err(DiscoveryError::Receive(e)),
+        };
+
+        if number_of_bytes > MAX_INCOMING_BEACON_SIZE {
+            trace!("Skipping datagram from '{}' larger than the largest beacon", source_address);
+            continue;
+        }
+
+        if let Some(beacon) = Beacon::parse_with_magic_number(&buffer[..number_of_bytes], source_address,
+                                                              magic_number) {
+            if beacon.service_name == service_name &&
+                seen.insert((beacon.service_ip.clone(), beacon.service_port)) {
+                beacons.push(beacon);
+            }
+        }
+    }
+
+    info!("Query for '{}' found {} services", String::from_utf8_lossy(service_name), beacons.len());
+    Ok(beacons)
+}
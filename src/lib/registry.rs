@@ -0,0 +1,477 @@
+use std::collections::HashMap;
+#[cfg(feature = "serde")]
+use std::io;
+#[cfg(feature = "serde")]
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use log::{trace, warn};
+use crate::{Beacon, BeaconListener, DiscoveryError, Result};
+
+/*
+    The longest the background thread blocks waiting for beacons before checking if the
+    registry has been dropped, and for expired services, however many other datagrams arrive
+ */
+pub(crate) const POLL_PERIOD: Duration = Duration::from_millis(250);
+
+/*
+    The first bytes of a gzip stream, to tell compressed snapshots from plain JSON ones
+ */
+#[cfg(feature = "serde")]
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+// The latest beacon from each instance, with when the instance expires if no other arrives
+type Services = HashMap<(String, u16), (Beacon, Instant)>;
+
+/// `ServiceEvent` is a change to the services tracked by a `ServiceRegistry`, see
+/// `ServiceRegistry::events()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ServiceEvent {
+    /// A beacon was received from an instance of a service that was not being tracked
+    Added(Beacon),
+    /// No beacon was received from an instance of a service within the registry's `ttl`, with
+    /// the last beacon that was received from it, or it sent a goodbye beacon (see
+    /// `BeaconSender::send_goodbye()`), with the goodbye beacon
+    Removed(Beacon),
+}
+
+/// `ServiceRegistry` keeps track of the services currently alive on the network, by receiving
+/// beacons with a `BeaconListener` on a background thread. Each instance of a service is keyed
+/// by its `(service_ip, service_port)` and expires `ttl` after the last beacon received from it
+/// (or after the lease in the beacon, if it has one, see `BeaconSender::with_lease()`), or as
+/// soon as a goodbye beacon is received from it. The background thread is stopped when the
+/// registry is dropped
+///
+/// ```
+/// use simpdiscoverylib::{BeaconSender, BeaconListener, ServiceRegistry};
+/// use std::time::Duration;
+/// use portpicker::pick_unused_port;
+///
+/// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+/// let listener = BeaconListener::new("registered".as_bytes(), broadcast_port)
+///     .expect("Could not create listener");
+/// let registry = ServiceRegistry::new(listener, Duration::from_secs(5));
+///
+/// let beacon = BeaconSender::new(8080, "registered".as_bytes(), broadcast_port)
+///     .expect("Could not create sender");
+/// beacon.send_one_beacon().expect("Could not send beacon");
+/// beacon.send_one_beacon().expect("Could not send beacon");
+///
+/// std::thread::sleep(Duration::from_millis(300));
+/// let services = registry.current_services();
+/// assert_eq!(services.len(), 1);
+/// assert_eq!(services[0].service_port, 8080);
+/// ```
+pub struct ServiceRegistry {
+    shared: Arc<Shared>,
+    receiver: Option<JoinHandle<()>>,
+    #[cfg(all(feature = "serde", feature = "compression"))]
+    compressed_snapshots: bool,
+}
+
+/*
+    The state shared between a registry and the thread or tasks receiving and expiring its services
+ */
+pub(crate) struct Shared {
+    services: Mutex<Services>,
+    subscribers: Mutex<Vec<Subscriber>>,
+    ttl: Duration,
+    stop: AtomicBool,
+}
+
+/*
+    Where a subscriber to the events of a registry receives them
+ */
+pub(crate) enum Subscriber {
+    Channel(Sender<ServiceEvent>),
+    #[cfg(feature = "tokio")]
+    Async(::tokio::sync::mpsc::UnboundedSender<ServiceEvent>),
+}
+
+impl Subscriber {
+    /*
+        Send `event` to the subscriber, returning false if it has dropped its receiver
+     */
+    fn send(&self, event: ServiceEvent) -> bool {
+        match self {
+            Subscriber::Channel(sender) => sender.send(event).is_ok(),
+            #[cfg(feature = "tokio")]
+            Subscriber::Async(sender) => sender.send(event).is_ok(),
+        }
+    }
+}
+
+impl ServiceRegistry {
+    /// Create a new `ServiceRegistry` of the services matching `listener`'s service name, with
+    /// instances expiring if no beacon is received from them for `ttl`, or for the lease of
+    /// their last beacon if it has one
+    pub fn new(listener: BeaconListener, ttl: Duration) -> Self {
+        Self::start(listener, Shared::new(ttl))
+    }
+
+    /// Create a new `ServiceRegistry` like `new()`, already tracking the services in the
+    /// snapshot saved to `path` by `save()`, as if a beacon had just been received from each,
+    /// e.g. so a restarted process knows them before their next beacons arrive. Snapshots are
+    /// read whether they were compressed or not, but reading compressed ones needs the
+    /// `compression` feature
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener, ServiceRegistry};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let listener = BeaconListener::new("persisted".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// let registry = ServiceRegistry::new(listener, Duration::from_secs(60));
+    /// BeaconSender::new(8080, "persisted".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender")
+    ///     .send_one_beacon().expect("Could not send beacon");
+    /// std::thread::sleep(Duration::from_millis(300));
+    ///
+    /// let path = std::env::temp_dir().join(format!("simpdiscover-{broadcast_port}.json"));
+    /// registry.save(&path).expect("Could not save snapshot");
+    /// drop(registry);
+    ///
+    /// let listener = BeaconListener::new("persisted".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// let registry = ServiceRegistry::load(listener, Duration::from_secs(60), &path)
+    ///     .expect("Could not load snapshot");
+    /// let services = registry.current_services();
+    /// assert_eq!(services.len(), 1);
+    /// assert_eq!(services[0].service_port, 8080);
+    /// # std::fs::remove_file(&path).expect("Could not remove snapshot");
+    /// ```
+    #[cfg(feature = "serde")]
+    pub fn load<P: AsRef<Path>>(listener: BeaconListener, ttl: Duration, path: P) -> Result<Self> {
+        let snapshot = inflate_snapshot(std::fs::read(path)?)?;
+        let beacons: Vec<Beacon> = serde_json::from_slice(&snapshot).map_err(io::Error::from)?;
+
+        let shared = Shared::new(ttl);
+        let log_prefix = listener.log_prefix();
+        for beacon in beacons {
+            shared.record(beacon, &log_prefix);
+        }
+        Ok(Self::start(listener, shared))
+    }
+
+    /*
+        Start the thread receiving beacons for the services in `shared`
+     */
+    fn start(listener: BeaconListener, shared: Shared) -> Self {
+        let shared = Arc::new(shared);
+
+        let receiver = {
+            let shared = shared.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = receive_loop(&listener, &shared) {
+                    warn!("{}ServiceRegistry stopped receiving beacons ({e})", listener.log_prefix());
+                }
+            })
+        };
+
+        Self {
+            shared,
+            receiver: Some(receiver),
+            #[cfg(all(feature = "serde", feature = "compression"))]
+            compressed_snapshots: false,
+        }
+    }
+
+    /// Gzip the snapshots written by `save()`, e.g. for registries tracking hundreds of services
+    /// with large metadata. `load()` tells compressed snapshots from plain ones by their first bytes
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconListener, ServiceRegistry};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let listener = BeaconListener::new("compact".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// let registry = ServiceRegistry::new(listener, Duration::from_secs(60))
+    ///     .with_compressed_snapshots();
+    ///
+    /// let path = std::env::temp_dir().join(format!("simpdiscover-{broadcast_port}.json.gz"));
+    /// registry.save(&path).expect("Could not save snapshot");
+    /// assert!(std::fs::read(&path).expect("Could not read snapshot").starts_with(&[0x1f, 0x8b]));
+    ///
+    /// let listener = BeaconListener::new("compact".as_bytes(), pick_unused_port().expect("No free port"))
+    ///     .expect("Could not create listener");
+    /// let loaded = ServiceRegistry::load(listener, Duration::from_secs(60), &path)
+    ///     .expect("Could not load snapshot");
+    /// assert!(loaded.current_services().is_empty());
+    /// # std::fs::remove_file(&path).expect("Could not remove snapshot");
+    /// ```
+    #[cfg(all(feature = "serde", feature = "compression"))]
+    pub fn with_compressed_snapshots(mut self) -> Self {
+        self.compressed_snapshots = true;
+        self
+    }
+
+    /// Save a snapshot of the current services (see `current_services()`) to `path` as JSON,
+    /// to create a registry already tracking them with `load()`. The snapshot is gzipped if
+    /// `with_compressed_snapshots()` was set
+    #[cfg(feature = "serde")]
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let snapshot = serde_json::to_vec(&self.current_services()).map_err(io::Error::from)?;
+        #[cfg(feature = "compression")]
+        let snapshot = if self.compressed_snapshots {
+            crate::compression::gzip(&snapshot)?
+        } else {
+            snapshot
+        };
+        std::fs::write(path, snapshot)?;
+        Ok(())
+    }
+
+    /// The services that have sent a beacon within the registry's `ttl` (or their lease), with
+    /// the latest beacon received from each
+    pub fn current_services(&self) -> Vec<Beacon> {
+        self.shared.current_services()
+    }
+
+    /// Get a channel of the `ServiceEvent`s from now on, as instances of services appear and
+    /// expire. Expiry is checked periodically, so an instance is removed within a fraction
+    /// of a second after `ttl` (or its lease) has passed since its last beacon. Each call returns a new
+    /// channel that receives every event; the channels are disconnected when the registry is dropped
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener, ServiceEvent, ServiceRegistry};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let listener = BeaconListener::new("fleeting".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// let registry = ServiceRegistry::new(listener, Duration::from_millis(500));
+    /// let events = registry.events();
+    ///
+    /// BeaconSender::new(8080, "fleeting".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender")
+    ///     .send_one_beacon().expect("Could not send beacon");
+    ///
+    /// match events.recv_timeout(Duration::from_secs(5)).expect("No event") {
+    ///     ServiceEvent::Added(beacon) => assert_eq!(beacon.service_port, 8080),
+    ///     event => panic!("Unexpected event {event:?}"),
+    /// }
+    /// match events.recv_timeout(Duration::from_secs(5)).expect("No event") {
+    ///     ServiceEvent::Removed(beacon) => assert_eq!(beacon.service_port, 8080),
+    ///     event => panic!("Unexpected event {event:?}"),
+    /// }
+    /// ```
+    ///
+    /// Services expire on time even while beacons for other services keep arriving on the port
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener, ServiceEvent, ServiceRegistry};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let listener = BeaconListener::new("drowned".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// let registry = ServiceRegistry::new(listener, Duration::from_millis(500));
+    /// let events = registry.events();
+    ///
+    /// let noise = BeaconSender::new(9090, "noise".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// std::thread::spawn(move || {
+    ///     noise.send_loop(Duration::from_millis(20)).expect("Could not enter send_loop");
+    /// });
+    /// BeaconSender::new(8080, "drowned".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender")
+    ///     .send_one_beacon().expect("Could not send beacon");
+    ///
+    /// assert!(matches!(events.recv_timeout(Duration::from_secs(5)), Ok(ServiceEvent::Added(_))));
+    /// assert!(matches!(events.recv_timeout(Duration::from_secs(5)), Ok(ServiceEvent::Removed(_))));
+    /// ```
+    ///
+    /// A service whose beacons carry a lease expires when the lease runs out, whatever the `ttl`
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener, ServiceEvent, ServiceRegistry};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let listener = BeaconListener::new("leased".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// let registry = ServiceRegistry::new(listener, Duration::from_secs(60));
+    /// let events = registry.events();
+    ///
+    /// BeaconSender::new(8080, "leased".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender")
+    ///     .with_lease(Duration::from_millis(300))
+    ///     .send_one_beacon().expect("Could not send beacon");
+    ///
+    /// assert!(matches!(events.recv_timeout(Duration::from_secs(5)), Ok(ServiceEvent::Added(_))));
+    /// assert!(matches!(events.recv_timeout(Duration::from_secs(5)), Ok(ServiceEvent::Removed(_))));
+    /// assert!(registry.current_services().is_empty());
+    /// ```
+    ///
+    /// A service that sends a goodbye beacon is removed without waiting for the `ttl`
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener, ServiceEvent, ServiceRegistry};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let listener = BeaconListener::new("leaving".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener");
+    /// let registry = ServiceRegistry::new(listener, Duration::from_secs(60));
+    /// let events = registry.events();
+    ///
+    /// let beacon = BeaconSender::new(8080, "leaving".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// beacon.send_one_beacon().expect("Could not send beacon");
+    /// assert!(matches!(events.recv_timeout(Duration::from_secs(5)), Ok(ServiceEvent::Added(_))));
+    ///
+    /// beacon.send_goodbye().expect("Could not send goodbye");
+    /// match events.recv_timeout(Duration::from_secs(5)).expect("No event") {
+    ///     ServiceEvent::Removed(beacon) => assert!(beacon.goodbye),
+    ///     event => panic!("Unexpected event {event:?}"),
+    /// }
+    /// assert!(registry.current_services().is_empty());
+    /// ```
+    pub fn events(&self) -> Receiver<ServiceEvent> {
+        let (sender, receiver) = channel();
+        self.shared.subscribe(Subscriber::Channel(sender));
+        receiver
+    }
+}
+
+impl Drop for ServiceRegistry {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::Relaxed);
+        if let Some(receiver) = self.receiver.take() {
+            let _ = receiver.join();
+        }
+    }
+}
+
+impl Shared {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        Shared {
+            services: Mutex::new(HashMap::new()),
+            subscribers: Mutex::new(vec![]),
+            ttl,
+            stop: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn subscribe(&self, subscriber: Subscriber) {
+        self.subscribers.lock().unwrap_or_else(|e| e.into_inner()).push(subscriber);
+    }
+
+    pub(crate) fn current_services(&self) -> Vec<Beacon> {
+        let mut services = self.lock_services();
+        self.expire(&mut services);
+        services.values().map(|(beacon, _)| beacon.clone()).collect()
+    }
+
+    /*
+        Expire the services whose ttl or lease has run out, and hence publish their events
+     */
+    pub(crate) fn prune(&self) {
+        self.expire(&mut self.lock_services());
+    }
+
+    /*
+        Refresh the service that sent `beacon`, or remove it if it is a goodbye beacon,
+        publishing an event if it was added or removed
+     */
+    pub(crate) fn record(&self, beacon: Beacon, log_prefix: &str) {
+        let mut services = self.lock_services();
+        self.expire(&mut services);
+
+        let key = (beacon.service_ip.clone(), beacon.service_port);
+        if beacon.goodbye {
+            if services.remove(&key).is_some() {
+                trace!("{log_prefix}Service at '{}:{}' said goodbye", key.0, key.1);
+                self.publish(ServiceEvent::Removed(beacon));
+            }
+            return;
+        }
+
+        trace!("{log_prefix}Refreshing service at '{}:{}'", key.0, key.1);
+        if !services.contains_key(&key) {
+            self.publish(ServiceEvent::Added(beacon.clone()));
+        }
+        let expires_at = Instant::now() + beacon.lease.unwrap_or(self.ttl);
+        services.insert(key, (beacon, expires_at));
+    }
+
+    fn lock_services(&self) -> MutexGuard<'_, Services> {
+        self.services.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /*
+        Remove the services whose ttl or lease has run out, publishing an event for each
+     */
+    fn expire(&self, services: &mut Services) {
+        let now = Instant::now();
+        let expired: Vec<(String, u16)> = services.iter()
+            .filter(|(_, (_, expires_at))| now >= *expires_at)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in expired {
+            if let Some((beacon, _)) = services.remove(&key) {
+                trace!("Service at '{}:{}' expired", key.0, key.1);
+                self.publish(ServiceEvent::Removed(beacon));
+            }
+        }
+    }
+
+    /*
+        Send `event` to every subscriber, forgetting those that have dropped their receiver
+     */
+    fn publish(&self, event: ServiceEvent) {
+        self.subscribers.lock().unwrap_or_else(|e| e.into_inner())
+            .retain(|subscriber| subscriber.send(event.clone()));
+    }
+}
+
+fn receive_loop(listener: &BeaconListener, shared: &Shared) -> Result<()> {
+    while !shared.stop.load(Ordering::Relaxed) {
+        // A deadline rather than a timeout, which would restart on every non-matching datagram
+        match listener.wait_deadline(Instant::now() + POLL_PERIOD) {
+            Ok(beacon) => shared.record(beacon, &listener.log_prefix()),
+            Err(e) if e.is_timeout() => shared.prune(),
+            Err(DiscoveryError::MalformedBeacon(reason)) => {
+                warn!("{}ServiceRegistry is receiving malformed datagrams ({reason})", listener.log_prefix());
+                shared.prune();
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+/*
+    The JSON of a snapshot, gunzipping it first if it was compressed
+ */
+#[cfg(all(feature = "serde", feature = "compression"))]
+fn inflate_snapshot(snapshot: Vec<u8>) -> io::Result<Vec<u8>> {
+    if snapshot.starts_with(&GZIP_MAGIC) {
+        crate::compression::gunzip(&snapshot)
+    } else {
+        Ok(snapshot)
+    }
+}
+
+#[cfg(all(feature = "serde", not(feature = "compression")))]
+fn inflate_snapshot(snapshot: Vec<u8>) -> io::Result<Vec<u8>> {
+    if snapshot.starts_with(&GZIP_MAGIC) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  "the snapshot is compressed, which needs the compression feature"));
+    }
+    Ok(snapshot)
+}
@@ -0,0 +1,176 @@
+use std::net::IpAddr;
+use std::time::Duration;
+use log::{trace, warn};
+use crate::{append_checksum, append_field, append_ip_field, check_payload_size, Beacon, BeaconListener,
+            BeaconSender, DiscoveryError, Result, ADVERTISED_IP_FIELD, CHECKSUM_SIZE, FIELD_HEADER_SIZE,
+            RELAYED_FIELD, SIGNATURE_FIELD};
+
+/*
+    The size of the signature field that ends a signed beacon, before its checksum
+ */
+const SIGNATURE_FIELD_SIZE: usize = FIELD_HEADER_SIZE + 32;
+
+/// `DiscoveryRelay` bridges beacons between two network segments, for routed networks where
+/// broadcasts don't cross subnets. Beacons matching its `BeaconListener` (e.g. bound on
+/// segment A) are re-broadcast by its `BeaconSender` (e.g. bound on segment B with
+/// `BeaconSender::new_bound()`), with the service IP of the original beacon advertised so
+/// clients on B can reach services on A. Only the sender's socket, broadcast address and signing
+/// key are used. Beacons are received like any other, so the listener's checks (e.g. its rate limit
+/// or verification key), counters and sink all apply to the beacons relayed.
+///
+/// Relayed beacons are flagged as such (see `Beacon::relayed`) and are never relayed again, so
+/// two relays can be run to bridge in both directions without beacons looping between them
+///
+/// ```
+/// use simpdiscoverylib::{BeaconSender, BeaconListener, DiscoveryRelay};
+/// use std::time::Duration;
+/// use portpicker::pick_unused_port;
+///
+/// let segment_a_port = pick_unused_port().expect("Could not get a free port for broadcast");
+/// let segment_b_port = pick_unused_port().expect("Could not get a free port for broadcast");
+///
+/// let relay = DiscoveryRelay::new(
+///     BeaconListener::new("relayed".as_bytes(), segment_a_port).expect("Could not create listener"),
+///     BeaconSender::new(1, "relay".as_bytes(), segment_b_port).expect("Could not create sender"));
+/// std::thread::spawn(move || relay.run().expect("Relay failed"));
+///
+/// let beacon = BeaconSender::new(8080, "relayed".as_bytes(), segment_a_port)
+///     .expect("Could not create sender");
+/// std::thread::spawn(move || {
+///     beacon.send_loop(Duration::from_millis(100)).expect("Could not enter send_loop");
+/// });
+///
+/// let listener = BeaconListener::new("relayed".as_bytes(), segment_b_port)
+///     .expect("Could not create listener");
+/// let beacon = listener.wait(None).expect("Failed to receive relayed beacon");
+/// assert!(beacon.relayed);
+/// assert_eq!(beacon.service_port, 8080);
+/// ```
+pub struct DiscoveryRelay {
+    listener: BeaconListener,
+    sender: BeaconSender,
+}
+
+impl DiscoveryRelay {
+    /// Create a new `DiscoveryRelay` that re-broadcasts beacons received by `listener` using `sender`.
+    /// The listener captures the raw payloads of beacons (see `BeaconListener::capture_raw_payloads()`),
+    /// to relay them unchanged apart from the fields the relay adds
+    pub fn new(listener: BeaconListener, sender: BeaconSender) -> Self {
+        Self {
+            listener: listener.capture_raw_payloads(),
+            sender,
+        }
+    }
+
+    /// Relay matching beacons forever. A beacon that can't be relayed, because it is too large
+    /// once flagged as relayed, because it is signed and the sender has no key to sign it again
+    /// with, or because sending it fails, is logged and skipped, so only failing to receive
+    /// beacons stops the relay
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener, DiscoveryRelay, MAX_SERVICE_NAME_LENGTH};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let segment_a_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let segment_b_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let relay = DiscoveryRelay::new(
+    ///     BeaconListener::new_with_prefix("relayed".as_bytes(), segment_a_port).expect("Could not create listener"),
+    ///     BeaconSender::new(1, "relay".as_bytes(), segment_b_port).expect("Could not create sender"));
+    /// std::thread::spawn(move || relay.run().expect("Relay failed"));
+    /// let listener = BeaconListener::new("relayed".as_bytes(), segment_b_port)
+    ///     .expect("Could not create listener");
+    ///
+    /// // A beacon with the longest service name has no room left for the relayed fields
+    /// let mut too_large = b"relayed".to_vec();
+    /// too_large.resize(MAX_SERVICE_NAME_LENGTH, b'x');
+    /// BeaconSender::new(8080, &too_large, segment_a_port).expect("Could not create sender")
+    ///     .send_one_beacon().expect("Could not send beacon");
+    ///
+    /// let beacon = BeaconSender::new(8080, "relayed".as_bytes(), segment_a_port)
+    ///     .expect("Could not create sender");
+    /// std::thread::spawn(move || {
+    ///     beacon.send_loop(Duration::from_millis(100)).expect("Could not enter send_loop");
+    /// });
+    /// assert!(listener.wait(Some(Duration::from_secs(5))).expect("Relay stopped").relayed);
+    /// ```
+    pub fn run(&self) -> Result<()> {
+        loop {
+            match self.relay_one(None) {
+                Ok(_) => {}
+                Err(e @ (DiscoveryError::PayloadTooLarge { .. } | DiscoveryError::SigningKeyRequired |
+                         DiscoveryError::Send(_))) =>
+                    warn!("{}Could not relay beacon, skipping it ({e})", self.listener.log_prefix()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Wait for the next beacon matching the listener's service name, that has not already been
+    /// relayed, and re-broadcast it. If `timeout` is `Some(Duration)` then it will block for that
+    /// duration on the reception of each beacon. Returns the beacon that was relayed, or
+    /// `DiscoveryError::PayloadTooLarge`, `DiscoveryError::SigningKeyRequired` or
+    /// `DiscoveryError::Send` if the beacon received could not be relayed
+    pub fn relay_one(&self, timeout: Option<Duration>) -> Result<Beacon> {
+        let beacon = self.listener.wait_where(timeout, |beacon| !beacon.relayed)?;
+        let relayed_payload = self.relayed_payload(&beacon)?;
+
+        trace!("{}Relaying Beacon '{}' from '{}' to: '{}'", self.listener.log_prefix(),
+            beacon.service_name_str(), beacon.source_addr, self.sender.broadcast_address);
+        check_payload_size(&relayed_payload)?;
+        self.sender.send_to_all(&relayed_payload)?;
+
+        let mut relayed = beacon;
+        relayed.relayed = true;
+        Ok(relayed)
+    }
+
+    /*
+        The payload of `beacon` with the relayed fields added. A signature has to stay the last
+        field, and the fields added break it, so the beacon is signed again after them with the
+        sender's key (as are unsigned beacons, if the sender has a key)
+     */
+    fn relayed_payload(&self, beacon: &Beacon) -> Result<Vec<u8>> {
+        let payload = beacon.raw_payload.as_deref().unwrap_or_default();
+        let mut relayed_payload = payload[..payload.len().saturating_sub(CHECKSUM_SIZE)].to_vec();
+        let signed = is_signed(&relayed_payload);
+        if signed {
+            relayed_payload.truncate(relayed_payload.len() - SIGNATURE_FIELD_SIZE);
+        }
+
+        // Keep the original service IP reachable, as the relayed beacon comes from the relay
+        if let Ok(service_ip) = beacon.service_ip.parse::<IpAddr>() {
+            append_ip_field(&mut relayed_payload, ADVERTISED_IP_FIELD, service_ip);
+        }
+        append_field(&mut relayed_payload, RELAYED_FIELD, &[]);
+        self.sign(&mut relayed_payload, signed)?;
+        append_checksum(&mut relayed_payload);
+        Ok(relayed_payload)
+    }
+
+    #[cfg(feature = "signed-beacons")]
+    fn sign(&self, payload: &mut Vec<u8>, signed: bool) -> Result<()> {
+        match &self.sender.signing_key {
+            Some(key) => crate::signing::append_signature(payload, key),
+            None if signed => return Err(DiscoveryError::SigningKeyRequired),
+            None => {}
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "signed-beacons"))]
+    fn sign(&self, _payload: &mut Vec<u8>, signed: bool) -> Result<()> {
+        if signed {
+            return Err(DiscoveryError::SigningKeyRequired);
+        }
+        Ok(())
+    }
+}
+
+/*
+    Whether the beacon in `payload` (without its checksum) ends with a signature field
+ */
+fn is_signed(payload: &[u8]) -> bool {
+    payload.len().checked_sub(SIGNATURE_FIELD_SIZE)
+        .is_some_and(|start| payload[start..start + FIELD_HEADER_SIZE] == [SIGNATURE_FIELD, 0, 32])
+}
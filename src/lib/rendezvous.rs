@@ -0,0 +1,299 @@
+//! TCP based discovery against a known rendezvous host, as a last resort on networks where UDP
+//! broadcast (or UDP entirely) is blocked. `BeaconSender`s register their beacon with a
+//! `TcpRendezvous` server and listeners query it by service name, getting back the same
+//! `Beacon`s they would have received by UDP.
+//!
+//! Each request is a single connection carrying a kind byte, a length and then the body:
+//!   * register: the sender's beacon payload. The server replies with a single byte: an ack, or a
+//!     refusal if it already holds as many registrations as it can
+//!   * query: the service name. The server replies with the number of matching registrations,
+//!     followed by the address each was registered from and its beacon payload
+//!
+//! ```
+//! use simpdiscoverylib::{BeaconSender, TcpRendezvous};
+//! use portpicker::pick_unused_port;
+//!
+//! let rendezvous = TcpRendezvous::bind("127.0.0.1:0").expect("Could not bind rendezvous");
+//! let server = rendezvous.local_addr().expect("Could not get rendezvous address");
+//! std::thread::spawn(move || rendezvous.serve().expect("Rendezvous failed"));
+//!
+//! let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+//! let beacon = BeaconSender::new(8080, "_my_service._tcp.local".as_bytes(), broadcast_port)
+//!     .expect("Could not create sender");
+//! TcpRendezvous::register(server, &beacon).expect("Could not register");
+//!
+//! let beacons = TcpRendezvous::query(server, "_my_service._tcp.local".as_bytes())
+//!     .expect("Could not query");
+//! assert_eq!(beacons.len(), 1);
+//! assert_eq!(beacons[0].service_port, 8080);
+//! assert_eq!(beacons[0].service_ip, "127.0.0.1");
+//! ```
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use log::{info, trace, warn};
+use crate::{array_of_u8_to_u16, u16_to_array_of_u8, Beacon, BeaconSender, DiscoveryError, Result, MAGIC_NUMBER};
+
+const REGISTER: u8 = 1;
+const QUERY: u8 = 2;
+const ACK: u8 = 1;
+const FULL: u8 = 0;
+
+/*
+    How long the server waits on a client to send its request or read the response, so a client
+    that stalls can't hold its thread (and connection) forever
+ */
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The default maximum number of beacons a `TcpRendezvous` keeps registered at once,
+/// see `TcpRendezvous::with_max_registrations()`
+pub const DEFAULT_MAX_REGISTRATIONS: usize = 1024;
+
+/*
+    A beacon registered with the rendezvous, and when it expires if the sender set a lease
+ */
+struct Registration {
+    source_address: SocketAddr,
+    payload: Vec<u8>,
+    beacon: Beacon,
+    expires: Option<Instant>,
+}
+
+/// `TcpRendezvous` is a server that `BeaconSender`s register with and listeners query over TCP.
+/// The associated functions `register()` and `query()` are the client side
+pub struct TcpRendezvous {
+    listener: TcpListener,
+    registrations: Arc<Mutex<Vec<Registration>>>,
+    max_registrations: usize,
+}
+
+impl TcpRendezvous {
+    /// Create a new `TcpRendezvous` server bound to `address`
+    pub fn bind<A: ToSocketAddrs>(address: A) -> Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        info!("Rendezvous bound to: {}", listener.local_addr()?);
+
+        Ok(Self {
+            listener,
+            registrations: Arc::new(Mutex::new(Vec::new())),
+            max_registrations: DEFAULT_MAX_REGISTRATIONS,
+        })
+    }
+
+    /// Keep at most `max_registrations` beacons registered at once (capped at `u16::MAX`, the
+    /// most a query response can hold), instead of `DEFAULT_MAX_REGISTRATIONS`. While the server
+    /// is full, registrations of new beacons are refused until others expire, while senders
+    /// already registered can still renew theirs
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, TcpRendezvous};
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let rendezvous = TcpRendezvous::bind("127.0.0.1:0").expect("Could not bind rendezvous")
+    ///     .with_max_registrations(1);
+    /// let server = rendezvous.local_addr().expect("Could not get rendezvous address");
+    /// std::thread::spawn(move || rendezvous.serve().expect("Rendezvous failed"));
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let first = BeaconSender::new(8080, "capped".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// TcpRendezvous::register(server, &first).expect("Could not register");
+    /// TcpRendezvous::register(server, &first).expect("Could not renew registration");
+    ///
+    /// let second = BeaconSender::new(8081, "capped".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender");
+    /// assert!(TcpRendezvous::register(server, &second).is_err());
+    /// ```
+    pub fn with_max_registrations(mut self, max_registrations: usize) -> Self {
+        self.max_registrations = max_registrations.min(u16::MAX as usize);
+        self
+    }
+
+    /// The local address the server is bound to
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Accept and handle client requests forever, each on its own thread.
+    /// Registrations are kept until the lease in their beacon (if any) expires. A connection that
+    /// can't be accepted, or whose request fails or times out, is logged and the server carries on
+    pub fn serve(&self) -> Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Rendezvous could not accept connection ({e})");
+                    continue;
+                }
+            };
+            let registrations = self.registrations.clone();
+            let max_registrations = self.max_registrations;
+            std::thread::spawn(move || {
+                if let Err(e) = handle_request(stream, &registrations, max_registrations) {
+                    warn!("Rendezvous could not handle request ({e})");
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Register the beacon of `sender` with the rendezvous server at `server`
+    pub fn register<A: ToSocketAddrs>(server: A, sender: &BeaconSender) -> Result<()> {
+        let mut stream = TcpStream::connect(server)?;
+        write_request(&mut stream, REGISTER, &sender.beacon_payload())?;
+
+        let mut ack = [0; 1];
+        stream.read_exact(&mut ack)?;
+        if ack[0] != ACK {
+            return Err(DiscoveryError::Io(io::Error::new(io::ErrorKind::InvalidData,
+                                      "SimpDiscover::TcpRendezvous registration was not acknowledged")));
+        }
+        Ok(())
+    }
+
+    /// Query the rendezvous server at `server` for all beacons registered for `service_name`
+    /// with the default magic number
+    pub fn query<A: ToSocketAddrs>(server: A, service_name: &[u8]) -> Result<Vec<Beacon>> {
+        Self::query_with_magic_number(server, service_name, MAGIC_NUMBER)
+    }
+
+    /// Query the rendezvous server at `server` like `query()`, for the beacons registered by
+    /// senders set to `magic_number` with `BeaconSender::with_magic_number()`. The server keeps
+    /// beacons with any magic number, and beacons with other magic numbers are left out
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, TcpRendezvous};
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let rendezvous = TcpRendezvous::bind("127.0.0.1:0").expect("Could not bind rendezvous");
+    /// let server = rendezvous.local_addr().expect("Could not get rendezvous address");
+    /// std::thread::spawn(move || rendezvous.serve().expect("Rendezvous failed"));
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let beacon = BeaconSender::new(8080, "namespaced".as_bytes(), broadcast_port)
+    ///     .expect("Could not create sender")
+    ///     .with_magic_number(0x5d15);
+    /// TcpRendezvous::register(server, &beacon).expect("Could not register");
+    ///
+    /// let beacons = TcpRendezvous::query_with_magic_number(server, "namespaced".as_bytes(), 0x5d15)
+    ///     .expect("Could not query");
+    /// assert_eq!(beacons.len(), 1);
+    /// assert!(TcpRendezvous::query(server, "namespaced".as_bytes()).expect("Could not query").is_empty());
+    /// ```
+    pub fn query_with_magic_number<A: ToSocketAddrs>(server: A, service_name: &[u8],
+                                                     magic_number: u16) -> Result<Vec<Beacon>> {
+        let mut stream = TcpStream::connect(server)?;
+        write_request(&mut stream, QUERY, service_name)?;
+
+        let count = array_of_u8_to_u16(&read_exactly(&mut stream, 2)?);
+        let mut beacons = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let address_length = read_exactly(&mut stream, 1)?[0] as usize;
+            let address = String::from_utf8_lossy(&read_exactly(&mut stream, address_length)?)
+                .parse::<SocketAddr>()
+                .map_err(|e| DiscoveryError::MalformedBeacon(format!("TcpRendezvous returned an invalid address ({e})")))?;
+            let payload_length = array_of_u8_to_u16(&read_exactly(&mut stream, 2)?) as usize;
+            let payload = read_exactly(&mut stream, payload_length)?;
+            if payload_magic_number(&payload) != Some(magic_number) {
+                continue;
+            }
+            let beacon = Beacon::parse_with_magic_number(&payload, address, magic_number)
+                .ok_or_else(|| DiscoveryError::MalformedBeacon("TcpRendezvous returned an invalid beacon".into()))?;
+            beacons.push(beacon);
+        }
+
+        Ok(beacons)
+    }
+}
+
+fn handle_request(mut stream: TcpStream, registrations: &Mutex<Vec<Registration>>,
+                  max_registrations: usize) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let source_address = stream.peer_addr()?;
+    let kind = read_exactly(&mut stream, 1)?[0];
+    let length = array_of_u8_to_u16(&read_exactly(&mut stream, 2)?) as usize;
+    let body = read_exactly(&mut stream, length)?;
+
+    let response = respond(kind, body, source_address, registrations, max_registrations)?;
+    stream.write_all(&response)
+}
+
+/*
+    The response to a request, built with the registrations locked but written after releasing
+    them, so a slow client doesn't hold up the others
+ */
+fn respond(kind: u8, body: Vec<u8>, source_address: SocketAddr, registrations: &Mutex<Vec<Registration>>,
+           max_registrations: usize) -> io::Result<Vec<u8>> {
+    let mut registrations = registrations.lock().unwrap_or_else(|e| e.into_inner());
+    let now = Instant::now();
+    registrations.retain(|registration| registration.expires.is_none_or(|expires| expires > now));
+
+    match kind {
+        REGISTER => {
+            let beacon = payload_magic_number(&body)
+                .and_then(|magic_number| Beacon::parse_with_magic_number(&body, source_address, magic_number))
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid beacon registered"))?;
+            registrations.retain(|registration|
+                registration.source_address.ip() != source_address.ip() ||
+                    registration.beacon.service_port != beacon.service_port ||
+                    registration.beacon.service_name != beacon.service_name);
+            if registrations.len() >= max_registrations {
+                warn!("Rendezvous is full, refusing beacon '{}' from '{}'", beacon.service_name_str(),
+                    source_address);
+                return Ok(vec![FULL]);
+            }
+            trace!("Registering beacon '{}' from '{}'", beacon.service_name_str(),
+                source_address);
+            registrations.push(Registration {
+                source_address,
+                payload: body,
+                expires: beacon.lease.map(|lease| now + lease),
+                beacon,
+            });
+            Ok(vec![ACK])
+        }
+        QUERY => {
+            let matching: Vec<&Registration> = registrations.iter()
+                .filter(|registration| registration.beacon.service_name == body)
+                .collect();
+            trace!("Query for '{}' matched {} registrations", String::from_utf8_lossy(&body),
+                matching.len());
+
+            let mut response = u16_to_array_of_u8(matching.len() as u16).to_vec();
+            for registration in matching {
+                let address = registration.source_address.to_string();
+                response.push(address.len() as u8);
+                response.extend_from_slice(address.as_bytes());
+                response.extend_from_slice(&u16_to_array_of_u8(registration.payload.len() as u16));
+                response.extend_from_slice(&registration.payload);
+            }
+            Ok(response)
+        }
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown request kind {kind}"))),
+    }
+}
+
+/*
+    The magic number a beacon payload starts with, so beacons with any magic number can be
+    registered
+ */
+fn payload_magic_number(payload: &[u8]) -> Option<u16> {
+    payload.get(..2).map(array_of_u8_to_u16)
+}
+
+fn write_request(stream: &mut TcpStream, kind: u8, body: &[u8]) -> io::Result<()> {
+    let mut request = vec![kind];
+    request.extend_from_slice(&u16_to_array_of_u8(body.len() as u16));
+    request.extend_from_slice(body);
+    stream.write_all(&request)
+}
+
+fn read_exactly(stream: &mut TcpStream, length: usize) -> io::Result<Vec<u8>> {
+    let mut buffer = vec![0; length];
+    stream.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
@@ -0,0 +1,106 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::Ordering;
+use crate::BeaconListener;
+
+/// `ValueConstraint` is what a `MetadataSchema` requires of the value of a metadata entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ValueConstraint {
+    /// Any value, including an empty one: the entry only has to be present
+    Any,
+    /// Any value except an empty one
+    NonEmpty,
+    /// A finite number, such as "8" or "-0.5"
+    Numeric,
+    /// One of the values listed
+    OneOf(Vec<String>),
+}
+
+impl ValueConstraint {
+    fn allows(&self, value: &str) -> bool {
+        match self {
+            ValueConstraint::Any => true,
+            ValueConstraint::NonEmpty => !value.is_empty(),
+            ValueConstraint::Numeric => value.parse::<f64>().is_ok_and(f64::is_finite),
+            ValueConstraint::OneOf(values) => values.iter().any(|allowed| allowed == value),
+        }
+    }
+}
+
+/// `MetadataSchema` lists the metadata entries the beacons of a fleet must have, with a
+/// `ValueConstraint` on the value of each, see `BeaconListener::require_schema()`. Entries it
+/// doesn't list are allowed. With the `serde` feature it can be read from e.g. a JSON file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MetadataSchema {
+    required: BTreeMap<String, ValueConstraint>,
+}
+
+impl MetadataSchema {
+    /// Create a new `MetadataSchema` that requires no entries
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Require beacons to have a metadata entry for `key` whose value satisfies `constraint`
+    pub fn require(mut self, key: &str, constraint: ValueConstraint) -> Self {
+        self.required.insert(key.to_string(), constraint);
+        self
+    }
+
+    /*
+        Why `metadata` doesn't conform to the schema, or None if it does
+     */
+    pub(crate) fn violation(&self, metadata: &HashMap<String, String>) -> Option<String> {
+        self.required.iter().find_map(|(key, constraint)| match metadata.get(key) {
+            None => Some(format!("it has no '{key}' metadata")),
+            Some(value) if !constraint.allows(value) =>
+                Some(format!("its '{key}' metadata '{value}' is not {constraint:?}")),
+            Some(_) => None,
+        })
+    }
+}
+
+impl BeaconListener {
+    /// Only accept beacons whose metadata conforms to `schema`, to enforce a discovery contract
+    /// across a fleet. Beacons missing a required entry, or with a value that breaks its
+    /// constraint, are dropped (logged, and counted, see `schema_rejected_count()`)
+    ///
+    /// ```
+    /// use simpdiscoverylib::{BeaconSender, BeaconListener, MetadataSchema, ValueConstraint};
+    /// use std::collections::HashMap;
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let schema = MetadataSchema::new()
+    ///     .require("version", ValueConstraint::Numeric)
+    ///     .require("role", ValueConstraint::OneOf(vec!["primary".into(), "replica".into()]));
+    /// let listener = BeaconListener::new("contracted".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener")
+    ///     .require_schema(schema);
+    ///
+    /// let fleet = [(8080, "two", "primary"), (8081, "2", "leader"), (8082, "2", "replica")];
+    /// for (service_port, version, role) in fleet {
+    ///     let metadata = HashMap::from([("version".into(), version.into()), ("role".into(), role.into())]);
+    ///     BeaconSender::new(service_port, "contracted".as_bytes(), broadcast_port)
+    ///         .expect("Could not create sender")
+    ///         .with_metadata(metadata).expect("Metadata is too large")
+    ///         .send_one_beacon().expect("Could not send beacon");
+    /// }
+    ///
+    /// let beacon = listener.wait(Some(Duration::from_secs(5))).expect("Failed to receive beacon");
+    /// assert_eq!(beacon.service_port, 8082);
+    /// assert_eq!(listener.schema_rejected_count(), 2);
+    /// ```
+    pub fn require_schema(mut self, schema: MetadataSchema) -> Self {
+        self.schema = Some(schema);
+        self
+    }
+
+    /// The number of beacons dropped because their metadata didn't conform to the schema set with
+    /// `require_schema()`, since the listener was created or `take_stats()` was last called
+    pub fn schema_rejected_count(&self) -> u64 {
+        self.counters.dropped_schema.load(Ordering::Relaxed)
+    }
+}
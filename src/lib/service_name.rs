@@ -0,0 +1,28 @@
+//! Serialization of `Beacon::service_name`: as a string when it is valid UTF-8, as is usual,
+//! otherwise as `{"base64": "..."}` so binary service names survive the round trip
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::Error;
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum ServiceName {
+    Text(String),
+    Binary { base64: String },
+}
+
+pub(crate) fn serialize<S: Serializer>(service_name: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    match std::str::from_utf8(service_name) {
+        Ok(text) => ServiceName::Text(text.to_string()),
+        Err(_) => ServiceName::Binary { base64: STANDARD.encode(service_name) },
+    }.serialize(serializer)
+}
+
+pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    match ServiceName::deserialize(deserializer)? {
+        ServiceName::Text(text) => Ok(text.into_bytes()),
+        ServiceName::Binary { base64 } => STANDARD.decode(base64).map_err(D::Error::custom),
+    }
+}
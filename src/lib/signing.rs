@@ -0,0 +1,127 @@
+//! Authenticated beacons, signed by a `BeaconSender` with an HMAC-SHA256 tag using a secret key
+//! shared with its listeners, so that other hosts on the network can't forge beacons for the
+//! service. The tag is sent in a field after all the others, just before the checksum, and is
+//! computed over all of the beacon that precedes it.
+//!
+//! Signing only authenticates where a beacon came from: its contents are still sent in the clear.
+//! A `DiscoveryRelay` adds fields to the beacons it relays, which break their tag, so it signs them
+//! again with the key of its `BeaconSender`, and refuses to relay signed beacons if it has none.
+//!
+//! ```
+//! use simpdiscoverylib::{BeaconSender, BeaconListener};
+//! use std::time::Duration;
+//! use portpicker::pick_unused_port;
+//!
+//! let port = pick_unused_port().expect("Could not get a free port");
+//! let listener = BeaconListener::new_verified("signed".as_bytes(), port, b"shared secret")
+//!     .expect("Could not create listener");
+//!
+//! for key in [&b"wrong secret"[..], b"shared secret"] {
+//!     BeaconSender::new_signed(8080, "signed".as_bytes(), port, key)
+//!         .expect("Could not create sender")
+//!         .send_one_beacon().expect("Could not send beacon");
+//! }
+//! BeaconSender::new(8081, "signed".as_bytes(), port)
+//!     .expect("Could not create sender")
+//!     .send_one_beacon().expect("Could not send beacon");
+//!
+//! let beacon = listener.wait(Some(Duration::from_secs(5))).expect("Failed to receive beacon");
+//! assert_eq!(beacon.service_port, 8080);
+//! assert!(listener.wait(Some(Duration::from_millis(200))).is_err());
+//! assert_eq!(listener.take_stats().dropped_unverified, 2);
+//! ```
+//!
+//! A relay that verifies the beacons it receives and signs the beacons it relays:
+//!
+//! ```
+//! use simpdiscoverylib::{BeaconSender, BeaconListener, DiscoveryRelay};
+//! use std::time::Duration;
+//! use portpicker::pick_unused_port;
+//!
+//! let segment_a_port = pick_unused_port().expect("Could not get a free port for broadcast");
+//! let segment_b_port = pick_unused_port().expect("Could not get a free port for broadcast");
+//! let relay = DiscoveryRelay::new(
+//!     BeaconListener::new_verified("signed".as_bytes(), segment_a_port, b"segment a secret")
+//!         .expect("Could not create listener"),
+//!     BeaconSender::new_signed(1, "relay".as_bytes(), segment_b_port, b"segment b secret")
+//!         .expect("Could not create sender"));
+//! std::thread::spawn(move || relay.run().expect("Relay failed"));
+//!
+//! let beacon = BeaconSender::new_signed(8080, "signed".as_bytes(), segment_a_port, b"segment a secret")
+//!     .expect("Could not create sender");
+//! std::thread::spawn(move || {
+//!     beacon.send_loop(Duration::from_millis(100)).expect("Could not enter send_loop");
+//! });
+//!
+//! let listener = BeaconListener::new_verified("signed".as_bytes(), segment_b_port, b"segment b secret")
+//!     .expect("Could not create listener");
+//! let beacon = listener.wait(Some(Duration::from_secs(5))).expect("Failed to receive relayed beacon");
+//! assert!(beacon.relayed);
+//! assert_eq!(beacon.service_port, 8080);
+//! ```
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use crate::{append_field, BeaconListener, BeaconSender, Result, CHECKSUM_SIZE, FIELD_HEADER_SIZE,
+            SIGNATURE_FIELD};
+
+const SIGNATURE_SIZE: usize = 32;
+
+impl BeaconSender {
+    /// Create a new `BeaconSender` like `new()`, that signs its beacons with `key` for listeners
+    /// created with `BeaconListener::new_verified()` using the same key
+    pub fn new_signed(service_port: u16, service_name: &[u8], broadcast_port: u16, key: &[u8]) -> Result<Self> {
+        Ok(Self::new(service_port, service_name, broadcast_port)?.with_signing_key(key))
+    }
+
+    /// Sign the beacons sent with `key`, e.g. for a sender created with `new_multicast()`
+    pub fn with_signing_key(mut self, key: &[u8]) -> Self {
+        self.signing_key = Some(key.to_vec());
+        self
+    }
+}
+
+impl BeaconListener {
+    /// Create a new `BeaconListener` like `new()`, that drops (and counts, see `take_stats()`) all
+    /// beacons not signed with `key` by a `BeaconSender` created with `BeaconSender::new_signed()`
+    pub fn new_verified(service_name: &[u8], listening_port: u16, key: &[u8]) -> Result<Self> {
+        Ok(Self::new(service_name, listening_port)?.with_verification_key(key))
+    }
+
+    /// Drop all beacons not signed with `key`, e.g. for a listener created with `new_multicast()`
+    pub fn with_verification_key(mut self, key: &[u8]) -> Self {
+        self.verification_key = Some(key.to_vec());
+        self
+    }
+}
+
+/*
+    The HMAC of `data` with `key`
+ */
+fn mac(key: &[u8], data: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac
+}
+
+/*
+    Append the signature field for the beacon in `payload`, which must not have its checksum yet
+ */
+pub(crate) fn append_signature(payload: &mut Vec<u8>, key: &[u8]) {
+    let tag = mac(key, payload).finalize().into_bytes();
+    append_field(payload, SIGNATURE_FIELD, &tag);
+}
+
+/*
+    Whether the beacon in `payload` (including its checksum) ends with a valid signature field
+ */
+pub(crate) fn verify(payload: &[u8], key: &[u8]) -> bool {
+    let signature_field_size = FIELD_HEADER_SIZE + SIGNATURE_SIZE;
+    let Some(signed_end) = payload.len().checked_sub(CHECKSUM_SIZE + signature_field_size) else {
+        return false;
+    };
+    let (signed, rest) = payload.split_at(signed_end);
+    let header = [SIGNATURE_FIELD, 0, SIGNATURE_SIZE as u8];
+    rest[..FIELD_HEADER_SIZE] == header &&
+        mac(key, signed).verify_slice(&rest[FIELD_HEADER_SIZE..signature_field_size]).is_ok()
+}
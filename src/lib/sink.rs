@@ -0,0 +1,76 @@
+use std::io::Write;
+use std::sync::Mutex;
+use crate::{Beacon, BeaconListener};
+
+/// `BeaconSink` records every beacon a `BeaconListener` receives, whether it matched the
+/// listener or not, e.g. to keep an audit trail or collect metrics (see
+/// `BeaconListener::with_sink()`). Beacons dropped by the listener, e.g. for exceeding its rate
+/// limit, are recorded as not matched. Datagrams that aren't beacons are not recorded.
+///
+/// It is implemented for closures taking the same arguments as `record()`, and for a `Mutex`
+/// around any writer (such as a `File`), which writes a line for each beacon
+pub trait BeaconSink: Send + Sync {
+    /// Record `beacon`, which `matched` the listener's service name (and predicate, if any)
+    fn record(&self, beacon: &Beacon, matched: bool);
+}
+
+impl<F: Fn(&Beacon, bool) + Send + Sync> BeaconSink for F {
+    fn record(&self, beacon: &Beacon, matched: bool) {
+        self(beacon, matched)
+    }
+}
+
+impl<W: Write + Send> BeaconSink for Mutex<W> {
+    fn record(&self, beacon: &Beacon, matched: bool) {
+        let mut writer = self.lock().unwrap_or_else(|e| e.into_inner());
+        // A sink that can't be written to must not stop beacons being received
+        let _ = writeln!(writer, "{} {} from {}", if matched { "matched" } else { "unmatched" }, beacon,
+                         beacon.source_addr);
+    }
+}
+
+impl BeaconListener {
+    /// Record every beacon received in `sink`, matching or not, as it is received
+    ///
+    /// ```
+    /// use simpdiscoverylib::{Beacon, BeaconSender, BeaconListener};
+    /// use std::sync::{Arc, Mutex};
+    /// use std::time::Duration;
+    /// use portpicker::pick_unused_port;
+    ///
+    /// let recorded = Arc::new(Mutex::new(vec![]));
+    /// let sink = {
+    ///     let recorded = recorded.clone();
+    ///     move |beacon: &Beacon, matched: bool| {
+    ///         recorded.lock().expect("Poisoned").push((beacon.service_name_str().into_owned(), matched));
+    ///     }
+    /// };
+    ///
+    /// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+    /// let listener = BeaconListener::new("recorded".as_bytes(), broadcast_port)
+    ///     .expect("Could not create listener")
+    ///     .with_sink(Box::new(sink));
+    /// for name in ["other", "recorded"] {
+    ///     BeaconSender::new(8080, name.as_bytes(), broadcast_port)
+    ///         .expect("Could not create sender")
+    ///         .send_one_beacon().expect("Could not send beacon");
+    /// }
+    ///
+    /// listener.wait(Some(Duration::from_secs(5))).expect("Failed to receive beacon");
+    /// assert_eq!(*recorded.lock().expect("Poisoned"),
+    ///            vec![("other".to_string(), false), ("recorded".to_string(), true)]);
+    /// ```
+    pub fn with_sink(mut self, sink: Box<dyn BeaconSink>) -> Self {
+        self.sink = Some(sink);
+        self
+    }
+
+    /*
+        Record `beacon` in the sink, if the listener has one
+     */
+    pub(crate) fn record(&self, beacon: &Beacon, matched: bool) {
+        if let Some(sink) = &self.sink {
+            sink.record(beacon, matched);
+        }
+    }
+}
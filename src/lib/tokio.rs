@@ -0,0 +1,426 @@
+//! Async versions of `BeaconListener`, `BeaconSender` and `ServiceRegistry` built on tokio, for
+//! applications that already run a tokio runtime and don't want to dedicate a thread to
+//! discovery. They are created from (and keep the options of) the sync versions, and
+//! must be created from within a tokio runtime.
+//!
+//! ```
+//! use simpdiscoverylib::tokio::{AsyncBeaconListener, AsyncBeaconSender};
+//! use std::time::Duration;
+//! use portpicker::pick_unused_port;
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+//! let listener = AsyncBeaconListener::new("_my_service._tcp.local".as_bytes(), broadcast_port)
+//!     .expect("Could not create listener");
+//!
+//! let sender = AsyncBeaconSender::new(8080, "_my_service._tcp.local".as_bytes(), broadcast_port)
+//!     .expect("Could not create sender");
+//! tokio::spawn(async move {
+//!     sender.send_loop(Duration::from_millis(100)).await.expect("Could not enter send_loop");
+//! });
+//!
+//! let beacon = listener.wait(Some(Duration::from_secs(5))).await.expect("Failed to receive beacon");
+//! assert_eq!(beacon.service_port, 8080);
+//! # }
+//! ```
+
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use ::tokio::io::ReadBuf;
+use ::tokio::net::UdpSocket;
+use ::tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use ::tokio::task::JoinHandle;
+use ::tokio::time;
+use futures_core::Stream;
+use log::{trace, warn};
+use crate::{check_payload_size, Beacon, BeaconListener, BeaconSender, DiscoveryError, ListenerStats,
+            Result, SendReport, ServiceEvent, Unmatched};
+use crate::registry::{Shared, Subscriber, POLL_PERIOD};
+
+/// `AsyncBeaconListener` waits for beacons like `BeaconListener`, without blocking a thread
+pub struct AsyncBeaconListener {
+    listener: BeaconListener,
+    socket: UdpSocket,
+    // The buffer and dropped datagram count used when polled as a `Stream`
+    stream_buffer: Vec<u8>,
+    stream_dropped: usize,
+}
+
+impl AsyncBeaconListener {
+    /// Create a new `AsyncBeaconListener` listening for beacons matching `service_name` on `port`
+    pub fn new(service_name: &[u8], port: u16) -> Result<Self> {
+        Self::from_listener(BeaconListener::new(service_name, port)?)
+    }
+
+    /// Create a new `AsyncBeaconListener` from a `BeaconListener`, keeping the options set on it
+    /// (rate limit, debounce window, allowed port range, metadata schema, maximum dropped datagrams,
+    /// burst coalescing, sink and label).
+    /// Its counters keep being updated and can be read with `take_stats()`
+    pub fn from_listener(listener: BeaconListener) -> Result<Self> {
+        let std_socket = listener.socket.try_clone()?;
+        std_socket.set_nonblocking(true)?;
+        let socket = UdpSocket::from_std(std_socket)?;
+
+        Ok(Self {
+            stream_buffer: listener.receive_buffer(),
+            stream_dropped: 0,
+            listener,
+            socket,
+        })
+    }
+
+    /// Get a snapshot of the listener's counters without resetting them, as `BeaconListener::stats()`
+    pub fn stats(&self) -> ListenerStats {
+        self.listener.stats()
+    }
+
+    /// Take a snapshot of the listener's counters and reset them, as `BeaconListener::take_stats()`.
+    /// The `BeaconListener` itself is not exposed, as its blocking calls would change the
+    /// blocking mode of the socket it shares with the `AsyncBeaconListener`
+    pub fn take_stats(&self) -> ListenerStats {
+        self.listener.take_stats()
+    }
+
+    /// Wait for a beacon matching the service name of the listener, as `BeaconListener::wait()`.
+    /// If `timeout` is `Some(Duration)` then it will wait for that duration on the reception of
+    /// each beacon, returning a `DiscoveryError::Timeout` with a `NoMatchReport` when it expires
+    pub async fn wait(&self, timeout: Option<Duration>) -> Result<Beacon> {
+        let mut buffer = self.listener.receive_buffer();
+        let mut unmatched = Unmatched::default();
+        let mut dropped = 0;
+
+        loop {
+            let received = match timeout {
+                Some(timeout) => match time::timeout(timeout, self.socket.recv_from(&mut buffer)).await {
+                    Ok(received) => received,
+                    Err(_) => return Err(DiscoveryError::Timeout(self.listener.no_match_report(&unmatched))),
+                },
+                None => self.socket.recv_from(&mut buffer).await,
+            };
+            let (number_of_bytes, source_address) = received.map_err(DiscoveryError::Receive)?;
+
+            if let Some(beacon) = self.matching(&buffer[..number_of_bytes], source_address, &mut dropped,
+                                                &mut unmatched)? {
+                return Ok(beacon);
+            }
+        }
+    }
+
+    /*
+        Accept and filter the datagram in `payload`, returning the matching beacon if it is one.
+        `dropped` counts the datagrams dropped in a row, failing when it reaches the maximum
+     */
+    fn matching(&self, payload: &[u8], source_address: SocketAddr, dropped: &mut usize,
+                unmatched: &mut Unmatched) -> Result<Option<Beacon>> {
+        let beacon = match self.listener.accept(payload, source_address) {
+            Some(beacon) => {
+                *dropped = 0;
+                beacon
+            }
+            None => {
+                *dropped += 1;
+                if self.listener.max_dropped.is_some_and(|max| *dropped >= max) {
+                    return Err(DiscoveryError::MalformedBeacon(
+                        format!("{dropped} datagrams in a row were dropped, the last from '{source_address}'")));
+                }
+                return Ok(None);
+            }
+        };
+
+        match self.listener.filter(beacon, unmatched) {
+            Some(beacon) if self.listener.coalesce_bursts =>
+                self.coalesce_burst(beacon).map(Some).map_err(DiscoveryError::Receive),
+            matched => Ok(matched),
+        }
+    }
+
+    /*
+        Consume the beacons already queued that repeat `beacon`, accepting and filtering each as
+        `BeaconListener` does
+     */
+    fn coalesce_burst(&self, mut beacon: Beacon) -> io::Result<Beacon> {
+        let mut buffer = self.listener.receive_buffer();
+
+        loop {
+            match self.socket.try_peek_from(&mut buffer) {
+                Ok((number_of_bytes, source_address)) => {
+                    match self.listener.parse(&buffer[..number_of_bytes], source_address) {
+                        Some(peeked) if peeked.service_ip == beacon.service_ip &&
+                            peeked.service_port == beacon.service_port &&
+                            peeked.service_name == beacon.service_name => {
+                            trace!("{}Coalescing burst beacon from '{}'", self.listener.log_prefix(), source_address);
+                            let (number_of_bytes, source_address) = self.socket.try_recv_from(&mut buffer)?;
+                            let next = self.listener.accept(&buffer[..number_of_bytes], source_address)
+                                .and_then(|next| self.listener.filter(next, &mut Unmatched::default()));
+                            if let Some(next) = next {
+                                beacon = next;
+                            }
+                        }
+                        _ => return Ok(beacon),
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(beacon),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// The beacons matching the service name of the listener as they arrive, with the same filtering
+/// as `wait()` but no timeout, e.g. to use with `tokio::select!` or stream combinators.
+/// Datagrams are only received as the stream is polled, so an unpolled stream applies back-pressure
+/// by leaving them queued in the socket. It ends only if the caller stops on an error
+///
+/// ```
+/// use simpdiscoverylib::tokio::{AsyncBeaconListener, AsyncBeaconSender};
+/// use std::time::Duration;
+/// use tokio_stream::StreamExt;
+/// use portpicker::pick_unused_port;
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+/// let mut listener = AsyncBeaconListener::new("streamed".as_bytes(), broadcast_port)
+///     .expect("Could not create listener");
+///
+/// let sender = AsyncBeaconSender::new(8080, "streamed".as_bytes(), broadcast_port)
+///     .expect("Could not create sender");
+/// tokio::spawn(async move {
+///     sender.send_loop(Duration::from_millis(50)).await.expect("Could not enter send_loop");
+/// });
+///
+/// let mut received = 0;
+/// while let Some(beacon) = listener.next().await {
+///     assert_eq!(beacon.expect("Could not receive beacon").service_port, 8080);
+///     received += 1;
+///     if received == 3 {
+///         break;
+///     }
+/// }
+/// # }
+/// ```
+impl Stream for AsyncBeaconListener {
+    type Item = Result<Beacon>;
+
+    fn poll_next(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // Moved out while polling, so the listener can be borrowed to filter beacons
+        let mut stream_buffer = std::mem::take(&mut self.stream_buffer);
+        let mut dropped = self.stream_dropped;
+        let mut unmatched = Unmatched::default();
+
+        let poll = loop {
+            let mut buffer = ReadBuf::new(&mut stream_buffer);
+            let source_address = match self.socket.poll_recv_from(context, &mut buffer) {
+                Poll::Ready(Ok(source_address)) => source_address,
+                Poll::Ready(Err(e)) => break Poll::Ready(Some(Err(DiscoveryError::Receive(e)))),
+                Poll::Pending => break Poll::Pending,
+            };
+
+            match self.matching(buffer.filled(), source_address, &mut dropped, &mut unmatched) {
+                Ok(None) => continue,
+                result => break Poll::Ready(result.transpose()),
+            }
+        };
+
+        self.stream_buffer = stream_buffer;
+        self.stream_dropped = dropped;
+        poll
+    }
+}
+
+/// `AsyncBeaconSender` sends beacons like `BeaconSender`, without blocking a thread
+pub struct AsyncBeaconSender {
+    sender: BeaconSender,
+    socket: UdpSocket,
+}
+
+impl AsyncBeaconSender {
+    /// Create a new `AsyncBeaconSender` to broadcast beacons for `service_name` and `service_port`
+    /// on `broadcast_port`
+    pub fn new(service_port: u16, service_name: &[u8], broadcast_port: u16) -> Result<Self> {
+        Self::from_sender(BeaconSender::new(service_port, service_name, broadcast_port)?)
+    }
+
+    /// Create a new `AsyncBeaconSender` from a `BeaconSender`, sending the same beacon (with any
+    /// lease, priority etc. set on it) to the same destination
+    pub fn from_sender(sender: BeaconSender) -> Result<Self> {
+        let std_socket = sender.socket.try_clone()?;
+        std_socket.set_nonblocking(true)?;
+        let socket = UdpSocket::from_std(std_socket)?;
+
+        Ok(Self {
+            sender,
+            socket,
+        })
+    }
+
+    /// The `BeaconSender` this was created from
+    pub fn sender(&self) -> &BeaconSender {
+        &self.sender
+    }
+
+    /// Send beacons forever, sleeping for `period` between each one. Beacons are not sent
+    /// while a readiness check set on the sender reports the service is not ready
+    pub async fn send_loop(&self, period: Duration) -> Result<()> {
+        loop {
+            if self.sender.ready() {
+                self.send_one_beacon().await?;
+            } else {
+                trace!("{}Service '{}' is not ready: not sending beacon", self.sender.log_prefix(),
+                    String::from_utf8_lossy(&self.sender.service_name));
+            }
+            time::sleep(period).await;
+        }
+    }
+
+    /// Send a single beacon to each of the sender's destinations, as `BeaconSender::send_one_beacon()`
+    pub async fn send_one_beacon(&self) -> Result<Vec<SendReport>> {
+        let payload = self.sender.next_beacon_payload();
+        check_payload_size(&payload)?;
+        let mut reports = vec![];
+        let mut error = None;
+        for destination in self.sender.all_destinations() {
+            trace!("{}Sending Beacon '{}' to: '{}'", self.sender.log_prefix(),
+                String::from_utf8_lossy(&self.sender.service_name), destination);
+            match self.sender.record_send(self.socket.send_to(&payload, destination).await) {
+                Ok(bytes) => reports.push(SendReport { bytes, destination }),
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(reports),
+        }
+    }
+}
+
+/// `AsyncServiceRegistry` keeps track of the services currently alive on the network like
+/// `ServiceRegistry`, with a task receiving beacons from an `AsyncBeaconListener` and a task
+/// expiring services on a `tokio::time::interval`, instead of a thread. Both tasks are stopped
+/// when the registry is dropped
+///
+/// ```
+/// use simpdiscoverylib::ServiceEvent;
+/// use simpdiscoverylib::tokio::{AsyncBeaconListener, AsyncBeaconSender, AsyncServiceRegistry};
+/// use std::time::Duration;
+/// use tokio_stream::StreamExt;
+/// use portpicker::pick_unused_port;
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let broadcast_port = pick_unused_port().expect("Could not get a free port for broadcast");
+/// let listener = AsyncBeaconListener::new("registered".as_bytes(), broadcast_port)
+///     .expect("Could not create listener");
+/// let registry = AsyncServiceRegistry::new(listener, Duration::from_millis(500));
+/// let mut events = registry.events();
+///
+/// AsyncBeaconSender::new(8080, "registered".as_bytes(), broadcast_port)
+///     .expect("Could not create sender")
+///     .send_one_beacon().await.expect("Could not send beacon");
+///
+/// match events.next().await.expect("No event") {
+///     ServiceEvent::Added(beacon) => assert_eq!(beacon.service_port, 8080),
+///     event => panic!("Unexpected event {event:?}"),
+/// }
+/// assert_eq!(registry.active_services().len(), 1);
+///
+/// match events.next().await.expect("No event") {
+///     ServiceEvent::Removed(beacon) => assert_eq!(beacon.service_port, 8080),
+///     event => panic!("Unexpected event {event:?}"),
+/// }
+/// assert!(registry.active_services().is_empty());
+/// # }
+/// ```
+pub struct AsyncServiceRegistry {
+    shared: Arc<Shared>,
+    receiver: JoinHandle<()>,
+    pruner: JoinHandle<()>,
+}
+
+impl AsyncServiceRegistry {
+    /// Create a new `AsyncServiceRegistry` of the services matching `listener`'s service name,
+    /// with instances expiring as in `ServiceRegistry::new()`. It must be created from within a
+    /// tokio runtime, which its tasks are spawned on
+    pub fn new(mut listener: AsyncBeaconListener, ttl: Duration) -> Self {
+        let shared = Arc::new(Shared::new(ttl));
+
+        let receiver = {
+            let shared = shared.clone();
+            ::tokio::spawn(async move {
+                let log_prefix = listener.listener.log_prefix();
+                loop {
+                    match std::future::poll_fn(|context| Pin::new(&mut listener).poll_next(context)).await {
+                        Some(Ok(beacon)) => shared.record(beacon, &log_prefix),
+                        Some(Err(DiscoveryError::MalformedBeacon(reason))) =>
+                            warn!("{log_prefix}AsyncServiceRegistry is receiving malformed datagrams ({reason})"),
+                        Some(Err(e)) => {
+                            warn!("{log_prefix}AsyncServiceRegistry stopped receiving beacons ({e})");
+                            break;
+                        }
+                        None => break,
+                    }
+                }
+            })
+        };
+
+        let pruner = {
+            let shared = shared.clone();
+            ::tokio::spawn(async move {
+                let mut interval = time::interval(POLL_PERIOD);
+                loop {
+                    interval.tick().await;
+                    shared.prune();
+                }
+            })
+        };
+
+        Self {
+            shared,
+            receiver,
+            pruner,
+        }
+    }
+
+    /// The services that have sent a beacon within the registry's `ttl` (or their lease), with
+    /// the latest beacon received from each, as `ServiceRegistry::current_services()`
+    pub fn active_services(&self) -> Vec<Beacon> {
+        self.shared.current_services()
+    }
+
+    /// Get a `Stream` of the `ServiceEvent`s from now on, as `ServiceRegistry::events()`.
+    /// Each call returns a new stream that receives every event; the streams end when the
+    /// registry is dropped
+    pub fn events(&self) -> ServiceEvents {
+        let (sender, receiver) = unbounded_channel();
+        self.shared.subscribe(Subscriber::Async(sender));
+        ServiceEvents { receiver }
+    }
+}
+
+impl Drop for AsyncServiceRegistry {
+    fn drop(&mut self) {
+        self.receiver.abort();
+        self.pruner.abort();
+    }
+}
+
+/// `ServiceEvents` is a `Stream` of the `ServiceEvent`s of an `AsyncServiceRegistry`, see
+/// `AsyncServiceRegistry::events()`
+pub struct ServiceEvents {
+    receiver: UnboundedReceiver<ServiceEvent>,
+}
+
+impl Stream for ServiceEvents {
+    type Item = ServiceEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(context)
+    }
+}
@@ -0,0 +1,215 @@
+use simpdiscoverylib::{Beacon, BeaconListener, BeaconSender};
+use env_logger::Builder;
+use std::collections::HashSet;
+use std::net::Ipv4Addr;
+use std::process::exit;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use log::LevelFilter;
+
+const USAGE: &str = "Usage: simpdiscover <announce|listen> [<service>] [options]
+
+When listening, the service name may be a glob pattern using '*' and '?', e.g. '_http._tcp.*',
+to print every service matching it seen before the timeout, instead of waiting for just one.
+
+Options:
+  --port <port>                 the port beacons are sent to and received on (default 9002)
+  --broadcast-addr <address>    the IPv4 broadcast or multicast address to use (default 255.255.255.255)
+  --service <name>              the service name to announce or wait for (default BeaconTestService)
+  --service-port <port>         the port of the service announced (default 15002)
+  --timeout <seconds>           how long to announce for, or to wait for a beacon (default forever)
+  --ttl <hops>                  the IP time-to-live of the beacons announced (default the OS's)";
+
+/*
+    The options common to both subcommands, with their defaults
+ */
+struct Options {
+    port: u16,
+    broadcast_addr: Ipv4Addr,
+    service: String,
+    service_port: u16,
+    timeout: Option<Duration>,
+    ttl: Option<u32>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            port: 9002,
+            broadcast_addr: Ipv4Addr::BROADCAST,
+            service: "BeaconTestService".into(),
+            service_port: 15002,
+            timeout: None,
+            ttl: None,
+        }
+    }
+}
+
+/*
+    A single discovery tool for debugging a LAN, that either announces a service or waits for a
+    beacon from one. Exits with status 0 on success, 1 if the discovery fails (e.g. no beacon
+    arrives within the timeout) and 2 if the arguments are invalid.
+ */
+fn main() {
+    let args : Vec<String> = std::env::args().skip(1).collect();
+    let (command, options) = match args.split_first() {
+        Some((command, rest)) if command == "announce" || command == "listen" =>
+            (command.as_str(), parse_options(rest).unwrap_or_else(|e| usage_error(&e))),
+        Some((command, _)) if command == "-h" || command == "--help" => {
+            println!("{}", USAGE);
+            return;
+        }
+        Some((command, _)) => usage_error(&format!("unknown subcommand: {}", command)),
+        None => usage_error("no subcommand given"),
+    };
+
+    let mut builder = Builder::from_default_env();
+    builder.filter_level(LevelFilter::Info).init();
+
+    let result = match command {
+        "announce" => announce(&options),
+        _ => listen(&options),
+    };
+
+    if let Err(e) = result {
+        eprintln!("simpdiscover {} failed: {}", command, e);
+        exit(1);
+    }
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}\n{}", message, USAGE);
+    exit(2);
+}
+
+/*
+    Parse the `--name value` pairs following the subcommand
+ */
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut args = args.iter();
+    while let Some(option) = args.next() {
+        if !option.starts_with("--") {
+            options.service = option.clone();
+            continue;
+        }
+        let value = args.next().ok_or_else(|| format!("missing value for option: {}", option))?;
+        match option.as_str() {
+            "--port" => options.port = parse_value(option, value, "a number from 0 to 65535")?,
+            "--broadcast-addr" => options.broadcast_addr = parse_value(option, value, "an IPv4 address")?,
+            "--service" => options.service = value.clone(),
+            "--service-port" => options.service_port = parse_value(option, value, "a number from 0 to 65535")?,
+            "--timeout" => options.timeout = Some(Duration::from_secs(parse_value(option, value, "seconds")?)),
+            "--ttl" => options.ttl = Some(parse_value(option, value, "a number of hops")?),
+            _ => return Err(format!("unknown option: {}", option)),
+        }
+    }
+    Ok(options)
+}
+
+fn parse_value<T: std::str::FromStr>(option: &str, value: &str, expected: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("invalid value for {}: {}, expected {}", option, value, expected))
+}
+
+/*
+    Send beacons including this host's name every second, until the timeout if there is one
+ */
+fn announce(options: &Options) -> simpdiscoverylib::Result<()> {
+    let mut builder = BeaconSender::builder()
+        .service_port(options.service_port)
+        .service_name(options.service.as_bytes())
+        .broadcast_port(options.port)
+        .broadcast_address(options.broadcast_addr);
+    if let Some(ttl) = options.ttl {
+        builder = builder.ttl(ttl);
+    }
+    let beacon = builder.build()?.with_hostname()?;
+
+    println!("Announcing service '{}' on port {} to {}:{}", options.service, options.service_port,
+             options.broadcast_addr, options.port);
+    let stop = Arc::new(AtomicBool::new(false));
+    if let Some(timeout) = options.timeout {
+        let stop = stop.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(timeout);
+            stop.store(true, Ordering::SeqCst);
+        });
+    }
+    beacon.send_loop_until(Duration::from_secs(1), stop)
+}
+
+/*
+    Wait for a beacon from the service, joining the multicast group if the address is one, or
+    for all the services matching it if it is a glob pattern
+ */
+fn listen(options: &Options) -> simpdiscoverylib::Result<()> {
+    if let Some(literal_prefix) = options.service.find(['*', '?']) {
+        return listen_matching(options, &options.service.as_bytes()[..literal_prefix]);
+    }
+
+    let listener = if options.broadcast_addr.is_multicast() {
+        BeaconListener::new_multicast(options.service.as_bytes(), options.broadcast_addr, options.port)?
+    } else {
+        BeaconListener::new(options.service.as_bytes(), options.port)?
+    };
+
+    println!("Waiting for a beacon from service '{}' on port {}", options.service, options.port);
+    let beacon = listener.wait(options.timeout)?;
+    println!("Beacon from {}: {}", host(&beacon), beacon);
+    Ok(())
+}
+
+/*
+    Print each service matching the glob pattern seen once, until the timeout. The listener
+    matches the literal `prefix` of the pattern, and the rest of it is matched on each beacon
+ */
+fn listen_matching(options: &Options, prefix: &[u8]) -> simpdiscoverylib::Result<()> {
+    if options.broadcast_addr.is_multicast() {
+        usage_error("glob patterns can't be used with a multicast --broadcast-addr");
+    }
+
+    let listener = BeaconListener::new_with_prefix(prefix, options.port)?;
+    println!("Listening for services matching '{}' on port {}", options.service, options.port);
+    let deadline = options.timeout.map(|timeout| Instant::now() + timeout);
+    let mut seen = HashSet::new();
+    loop {
+        let timeout = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        if timeout.is_some_and(|timeout| timeout.is_zero()) {
+            return Ok(());
+        }
+        let beacon = match listener.wait_where(timeout,
+                                               |beacon| glob_matches(options.service.as_bytes(), &beacon.service_name)) {
+            Ok(beacon) => beacon,
+            Err(e) if e.is_timeout() => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        if seen.insert((beacon.service_name.clone(), beacon.service_ip.clone(), beacon.service_port)) {
+            println!("{} on port {} at {}", beacon.service_name_str(), beacon.service_port, host(&beacon));
+        }
+    }
+}
+
+/*
+    The host a beacon came from, by name as well as IP if the sender included its host name
+ */
+fn host(beacon: &Beacon) -> String {
+    match &beacon.hostname {
+        Some(hostname) => format!("{} ({})", hostname, beacon.service_ip),
+        None => beacon.service_ip.clone(),
+    }
+}
+
+/*
+    Whether `name` matches the glob `pattern`, where '*' matches any run of bytes and '?' any one
+ */
+fn glob_matches(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| glob_matches(rest, &name[skip..])),
+        Some((&expected, rest)) => match name.split_first() {
+            Some((&actual, name)) => (expected == b'?' || expected == actual) && glob_matches(rest, name),
+            None => false,
+        },
+    }
+}
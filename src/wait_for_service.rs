@@ -0,0 +1,59 @@
+use simpdiscoverylib::BeaconListener;
+use env_logger::Builder;
+use std::process::exit;
+use std::time::Duration;
+use log::LevelFilter;
+
+const USAGE: &str = "Usage: wait-for-service [-v] <service-name> <port> <timeout-seconds>";
+
+/*
+    Exit with status 0 if a beacon for the service arrives within the timeout, 1 if it doesn't
+    and 2 if the arguments are invalid. Nothing is printed unless '-v' is given.
+ */
+fn main() {
+    let mut args : Vec<String> = std::env::args().skip(1).collect();
+    let verbose = match args.iter().position(|arg| arg == "-v" || arg == "--verbose") {
+        Some(index) => {
+            args.remove(index);
+            true
+        }
+        None => false,
+    };
+
+    if verbose {
+        let mut builder = Builder::from_default_env();
+        builder.filter_level(LevelFilter::Info).init();
+    }
+
+    if args.len() != 3 {
+        eprintln!("{}", USAGE);
+        exit(2);
+    }
+
+    let port = args[1].parse::<u16>().unwrap_or_else(|_| {
+        eprintln!("invalid port: {}, expected a number from 0 to 65535\n{}", args[1], USAGE);
+        exit(2);
+    });
+
+    let timeout = args[2].parse::<u64>().unwrap_or_else(|_| {
+        eprintln!("invalid timeout: {}, expected seconds\n{}", args[2], USAGE);
+        exit(2);
+    });
+
+    let result = BeaconListener::new(args[0].as_bytes(), port)
+        .and_then(|listener| listener.wait(Some(Duration::from_secs(timeout))));
+
+    match result {
+        Ok(beacon) => {
+            if verbose {
+                println!("Beacon {}", beacon);
+            }
+        }
+        Err(e) => {
+            if verbose {
+                println!("No beacon from service '{}' ({})", args[0], e);
+            }
+            exit(1);
+        }
+    }
+}